@@ -0,0 +1,354 @@
+/*
+ * Copyright (C) 2019 Josh Gao
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::{Path, PathBuf};
+
+use super::config::{AuthConfig, Config};
+
+/// Credential callback for libgit2, which tries each of the sources of credentials in an AuthConfig in turn.
+///
+/// libgit2 calls back again whenever the server rejects what it was given, so each source is only tried once, and the
+/// callback fails when it runs out.
+pub struct Credentials {
+  auth: AuthConfig,
+  git_config: Option<git2::Config>,
+  ssh_attempts: usize,
+  userpass_attempts: usize,
+}
+
+impl Credentials {
+  pub fn new(auth: &AuthConfig) -> Credentials {
+    Credentials::with_git_config(auth, git2::Config::open_default().ok())
+  }
+
+  /// Create a credential callback that reads git's http.cookiefile and credential helpers from `git_config`.
+  fn with_git_config(auth: &AuthConfig, git_config: Option<git2::Config>) -> Credentials {
+    Credentials {
+      auth: auth.clone(),
+      git_config,
+      ssh_attempts: 0,
+      userpass_attempts: 0,
+    }
+  }
+
+  pub fn get(
+    &mut self,
+    url: &str,
+    username: Option<&str>,
+    allowed: git2::CredentialType,
+  ) -> Result<git2::Cred, git2::Error> {
+    if allowed.contains(git2::CredentialType::USERNAME) {
+      return git2::Cred::username(username.unwrap_or("git"));
+    }
+
+    if allowed.contains(git2::CredentialType::SSH_KEY) {
+      let username = username.unwrap_or("git");
+      while let Some(cred) = self.next_ssh_key(username) {
+        if let Ok(cred) = cred {
+          return Ok(cred);
+        }
+      }
+    }
+
+    if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+      while let Some(cred) = self.next_userpass(url, username) {
+        if let Some((username, password)) = cred {
+          return git2::Cred::userpass_plaintext(&username, &password);
+        }
+      }
+    }
+
+    Err(git2::Error::from_str(&format!("no credentials accepted for {}", url)))
+  }
+
+  fn next_ssh_key(&mut self, username: &str) -> Option<Result<git2::Cred, git2::Error>> {
+    let attempt = self.ssh_attempts;
+    self.ssh_attempts += 1;
+
+    let agent = if self.auth.ssh_agent { 1 } else { 0 };
+    if attempt < agent {
+      return Some(git2::Cred::ssh_key_from_agent(username));
+    }
+
+    let key = self.auth.ssh_keys.get(attempt - agent)?;
+    let key = match expand(key) {
+      Some(key) if key.exists() => key,
+      _ => return Some(Err(git2::Error::from_str("missing key"))),
+    };
+    let public_key = PathBuf::from(format!("{}.pub", key.display()));
+    let public_key = if public_key.exists() {
+      Some(public_key.as_path())
+    } else {
+      None
+    };
+    Some(git2::Cred::ssh_key(username, public_key, &key, None))
+  }
+
+  fn next_userpass(&mut self, url: &str, username: Option<&str>) -> Option<Option<(String, String)>> {
+    let attempt = self.userpass_attempts;
+    self.userpass_attempts += 1;
+
+    let host = url::Url::parse(url).ok()?.host_str()?.to_string();
+    match attempt {
+      0 => {
+        let cookie_file = self.auth.cookie_file.clone().or_else(|| {
+          self
+            .git_config
+            .as_ref()
+            .and_then(|config| config.get_string("http.cookiefile").ok())
+        });
+        Some(cookie_file.and_then(|path| cookie_credentials(&expand(&path)?, &host)))
+      }
+
+      1 => Some(
+        self
+          .auth
+          .netrc
+          .as_ref()
+          .and_then(|path| netrc_credentials(&expand(path)?, &host)),
+      ),
+
+      2 if self.auth.credential_helper => {
+        let config = self.git_config.as_ref()?;
+        Some(
+          git2::CredentialHelper::new(url)
+            .config(config)
+            .username(username)
+            .execute(),
+        )
+      }
+
+      _ => None,
+    }
+  }
+}
+
+fn expand(path: &str) -> Option<PathBuf> {
+  Config::expand_path(path).ok()
+}
+
+/// Find credentials for a host in a Netscape format cookie file, like the .gitcookies that Gerrit hands out.
+///
+/// libgit2 can't send cookies, but Gerrit's cookie values are `<username>=<password>`, which it also accepts over
+/// basic authentication.
+fn cookie_credentials(path: &Path, host: &str) -> Option<(String, String)> {
+  let contents = std::fs::read_to_string(path).ok()?;
+  for line in contents.lines() {
+    let line = line.trim_start_matches("#HttpOnly_");
+    if line.starts_with('#') {
+      continue;
+    }
+
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 7 {
+      continue;
+    }
+
+    let (domain, include_subdomains, value) = (fields[0], fields[1] == "TRUE", fields[6]);
+    let domain = domain.trim_start_matches('.');
+    let matches = host == domain || (include_subdomains && host.ends_with(&format!(".{}", domain)));
+    if !matches {
+      continue;
+    }
+
+    let mut split = value.splitn(2, '=');
+    if let (Some(username), Some(password)) = (split.next(), split.next()) {
+      return Some((username.to_string(), password.to_string()));
+    }
+  }
+  None
+}
+
+/// Find credentials for a host in a netrc file.
+fn netrc_credentials(path: &Path, host: &str) -> Option<(String, String)> {
+  let contents = std::fs::read_to_string(path).ok()?;
+  let mut tokens = contents.split_whitespace();
+
+  let mut matched = false;
+  let mut login = None;
+  let mut password = None;
+  while let Some(token) = tokens.next() {
+    match token {
+      "machine" | "default" => {
+        if matched {
+          break;
+        }
+        matched = token == "default" || tokens.next() == Some(host);
+        login = None;
+        password = None;
+      }
+      "login" => login = tokens.next(),
+      "password" => password = tokens.next(),
+      _ => {}
+    }
+  }
+
+  match (matched, login, password) {
+    (true, Some(login), Some(password)) => Some((login.to_string(), password.to_string())),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::io::{BufRead, BufReader, Write};
+  use std::sync::{Arc, Mutex};
+
+  // alice:s3cret
+  const AUTHORIZATION: &str = "Basic YWxpY2U6czNjcmV0";
+
+  /// Serve an empty git repository over HTTP, to anyone who logs in as alice, and record the Authorization headers
+  /// that clients send.
+  fn serve() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://127.0.0.1:{}/repo.git", listener.local_addr().unwrap().port());
+    let attempts = Arc::new(Mutex::new(Vec::new()));
+
+    let server_attempts = Arc::clone(&attempts);
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut authorization = None;
+        for line in BufReader::new(&stream).lines() {
+          let line = line.unwrap();
+          if line.is_empty() {
+            break;
+          }
+          let mut split = line.splitn(2, ": ");
+          if let (Some(name), Some(value)) = (split.next(), split.next()) {
+            if name.eq_ignore_ascii_case("authorization") {
+              authorization = Some(value.to_string());
+            }
+          }
+        }
+
+        let accepted = match authorization {
+          Some(authorization) => {
+            let accepted = authorization == AUTHORIZATION;
+            server_attempts.lock().unwrap().push(authorization);
+            accepted
+          }
+          None => false,
+        };
+        let response = if accepted {
+          let body = "001e# service=git-upload-pack\n00000000";
+          format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-git-upload-pack-advertisement\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+          )
+        } else {
+          "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"git\"\r\nContent-Length: 0\r\n\
+           Connection: close\r\n\r\n"
+            .to_string()
+        };
+        let _ = stream.write_all(response.as_bytes());
+      }
+    });
+
+    (url, attempts)
+  }
+
+  /// Connect to a server with the credentials, and return the Authorization headers that the server saw.
+  fn connect(auth: &AuthConfig, git_config: Option<git2::Config>) -> Result<Vec<String>, git2::Error> {
+    let (url, attempts) = serve();
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_bare(dir.path()).unwrap();
+    let mut remote = repo.remote_anonymous(&url).unwrap();
+
+    let mut credentials = Credentials::with_git_config(auth, git_config);
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| credentials.get(url, username, allowed));
+    remote.connect_auth(git2::Direction::Fetch, Some(callbacks), None)?;
+
+    let attempts = attempts.lock().unwrap().clone();
+    Ok(attempts)
+  }
+
+  fn auth_config() -> AuthConfig {
+    AuthConfig {
+      ssh_agent: false,
+      ssh_keys: Vec::new(),
+      cookie_file: None,
+      netrc: None,
+      credential_helper: false,
+    }
+  }
+
+  #[test]
+  fn cookie_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let cookie_file = dir.path().join("cookies");
+    std::fs::write(
+      &cookie_file,
+      "# Netscape HTTP Cookie File\n#HttpOnly_127.0.0.1\tFALSE\t/\tTRUE\t2147483647\to\talice=s3cret\n",
+    )
+    .unwrap();
+
+    let auth = AuthConfig {
+      cookie_file: Some(cookie_file.to_str().unwrap().into()),
+      ..auth_config()
+    };
+    assert_eq!(connect(&auth, None).unwrap(), vec![AUTHORIZATION]);
+  }
+
+  #[test]
+  fn netrc() {
+    let dir = tempfile::tempdir().unwrap();
+    let cookie_file = dir.path().join("cookies");
+    let netrc = dir.path().join("netrc");
+    std::fs::write(&cookie_file, "127.0.0.1\tFALSE\t/\tTRUE\t2147483647\to\talice=wrong\n").unwrap();
+    std::fs::write(
+      &netrc,
+      "machine example.com login bob password hunter2\nmachine 127.0.0.1\n  login alice\n  password s3cret\n",
+    )
+    .unwrap();
+
+    // The cookie file is tried first, and netrc when the server rejects it.
+    let auth = AuthConfig {
+      cookie_file: Some(cookie_file.to_str().unwrap().into()),
+      netrc: Some(netrc.to_str().unwrap().into()),
+      ..auth_config()
+    };
+    let attempts = connect(&auth, None).unwrap();
+    assert_eq!(attempts.len(), 2);
+    assert_eq!(attempts[1], AUTHORIZATION);
+  }
+
+  #[test]
+  fn credential_helper() {
+    let dir = tempfile::tempdir().unwrap();
+    let git_config_path = dir.path().join("gitconfig");
+    std::fs::write(
+      &git_config_path,
+      "[credential]\n  helper = \"!f() { test $1 = get && echo username=alice && echo password=s3cret; }; f\"\n",
+    )
+    .unwrap();
+
+    let auth = AuthConfig {
+      credential_helper: true,
+      ..auth_config()
+    };
+    let git_config = git2::Config::open(&git_config_path).unwrap();
+    assert_eq!(connect(&auth, Some(git_config)).unwrap(), vec![AUTHORIZATION]);
+
+    // Without the helper, there's nothing left to try.
+    assert!(connect(&auth_config(), None).is_err());
+  }
+}
@@ -29,6 +29,10 @@ use failure::ResultExt;
 pub struct Config {
   remotes: Vec<RemoteConfig>,
   depots: BTreeMap<String, DepotConfig>,
+
+  /// Paths relative to the root of a tree that `pore status --stray` shouldn't report, such as build output.
+  #[serde(default)]
+  stray_ignore: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,11 +41,55 @@ pub struct RemoteConfig {
   pub url: String,
   pub manifest: String,
   pub depot: String,
+
+  #[serde(default)]
+  pub auth: AuthConfig,
+}
+
+/// Where to look for credentials when a remote asks for them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+  /// Try the keys in ssh-agent for ssh remotes.
+  pub ssh_agent: bool,
+
+  /// Private keys to try for ssh remotes, after ssh-agent.
+  pub ssh_keys: Vec<String>,
+
+  /// Gerrit cookie file for https remotes, in the format of git's http.cookiefile.
+  /// Defaults to git's http.cookiefile if unspecified.
+  pub cookie_file: Option<String>,
+
+  /// netrc file to look up https credentials in.
+  pub netrc: Option<String>,
+
+  /// Ask git's configured credential helpers for https credentials.
+  pub credential_helper: bool,
+}
+
+impl Default for AuthConfig {
+  fn default() -> AuthConfig {
+    AuthConfig {
+      ssh_agent: true,
+      ssh_keys: vec![
+        "~/.ssh/id_ed25519".into(),
+        "~/.ssh/id_ecdsa".into(),
+        "~/.ssh/id_rsa".into(),
+      ],
+      cookie_file: None,
+      netrc: Some("~/.netrc".into()),
+      credential_helper: true,
+    }
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct DepotConfig {
   path: String,
+
+  /// Partial clone filter (e.g. "blob:none") to fetch into the depot with. Objects that are filtered out are fetched on
+  /// demand, so this trades disk space for network access when history is inspected.
+  filter: Option<String>,
 }
 
 impl Default for Config {
@@ -53,19 +101,23 @@ impl Default for Config {
           url: "https://android.googlesource.com/".into(),
           manifest: "platform/manifest".into(),
           depot: "android".into(),
+          auth: AuthConfig::default(),
         },
         RemoteConfig {
           name: "aosp-persistent-https".into(),
           url: "persistent-https://android.googlesource.com/".into(),
           manifest: "platform/manifest".into(),
           depot: "android".into(),
+          auth: AuthConfig::default(),
         },
       ],
       depots: btreemap! {
         "android".into() => DepotConfig {
           path: "~/.pore/android".into(),
+          filter: None,
         },
       },
+      stray_ignore: vec!["out".into()],
     }
   }
 }
@@ -78,11 +130,15 @@ impl Config {
     Ok(config)
   }
 
-  fn expand_path(path: &str) -> Result<PathBuf, Error> {
+  pub fn expand_path(path: &str) -> Result<PathBuf, Error> {
     let path = shellexpand::full(path).context("shell expansion failed")?;
     Ok(path.into_owned().into())
   }
 
+  pub fn stray_ignore(&self) -> &[String] {
+    &self.stray_ignore
+  }
+
   pub fn find_remote(&self, remote_name: &str) -> Result<RemoteConfig, Error> {
     for remote in &self.remotes {
       if remote.name == remote_name {
@@ -93,6 +149,23 @@ impl Config {
     Err(format_err!("unknown remote {}", remote_name))
   }
 
+  /// Find the configured remote for a remote declared in a manifest, by its name, or else by the longest configured URL
+  /// that its URL starts with.
+  pub fn find_manifest_remote(&self, remote_name: &str, url: &str) -> Option<RemoteConfig> {
+    self
+      .remotes
+      .iter()
+      .find(|remote| remote.name == remote_name)
+      .or_else(|| {
+        self
+          .remotes
+          .iter()
+          .filter(|remote| url.starts_with(&remote.url))
+          .max_by_key(|remote| remote.url.len())
+      })
+      .cloned()
+  }
+
   pub fn find_depot(&self, depot: &str) -> Result<Depot, Error> {
     let depot_config = self
       .depots
@@ -100,6 +173,6 @@ impl Config {
       .ok_or_else(|| format_err!("unknown depot {}", depot))?;
     let path = Config::expand_path(&depot_config.path).context(format!("failed to expand path for depot {}", depot))?;
 
-    Depot::new(depot.to_string(), path)
+    Depot::new(depot.to_string(), path, depot_config.filter.clone())
   }
 }
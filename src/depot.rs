@@ -14,23 +14,89 @@
  * limitations under the License.
  */
 
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
 use failure::Error;
 use failure::ResultExt;
 
+use super::auth;
 use super::config;
+use super::lock::FileLock;
 use super::util;
 
 #[derive(Clone, Debug)]
 pub struct Depot {
   name: String,
   path: PathBuf,
+  filter: Option<String>,
 }
 
+/// Progress of a fetch, as reported by libgit2 or git.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransferProgress {
+  pub received_objects: usize,
+  pub total_objects: usize,
+  pub received_bytes: usize,
+}
+
+impl TransferProgress {
+  /// Parse a progress line from git fetch, e.g. `Receiving objects:  45% (450/1000), 1.20 MiB | 500.00 KiB/s`.
+  fn parse(line: &str) -> Option<TransferProgress> {
+    // Small fetches are unpacked into loose objects as they're received, instead of being kept as a pack.
+    let line = line
+      .strip_prefix("Receiving objects:")
+      .or_else(|| line.strip_prefix("Unpacking objects:"))?;
+    let open = line.find('(')?;
+    let close = open + line[open..].find(')')?;
+    let mut counts = line[open + 1..close].split('/');
+    let received_objects = counts.next()?.parse().ok()?;
+    let total_objects = counts.next()?.parse().ok()?;
+
+    // The byte count only shows up once there's been enough of a transfer to report.
+    let mut received_bytes = 0;
+    let size = line[close + 1..].trim_start_matches(',').split('|').next()?;
+    let mut words = size.split_whitespace();
+    if let (Some(number), Some(unit)) = (words.next(), words.next()) {
+      let multiplier = match unit {
+        "bytes" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+      };
+      received_bytes = (number.parse::<f64>().ok()? * multiplier) as usize;
+    }
+
+    Some(TransferProgress {
+      received_objects,
+      total_objects,
+      received_bytes,
+    })
+  }
+}
+
+/// Name of the remote that checkouts in a partial clone depot fetch missing objects from.
+const PROMISOR_REMOTE: &str = "pore-promisor";
+
 impl Depot {
-  pub fn new(name: String, path: PathBuf) -> Result<Depot, Error> {
-    Ok(Depot { name, path })
+  pub fn new(name: String, path: PathBuf, filter: Option<String>) -> Result<Depot, Error> {
+    Ok(Depot { name, path, filter })
+  }
+
+  /// Mark a remote of a repository as a partial clone promisor, from which filtered out objects are fetched on demand.
+  fn set_promisor(repo: &git2::Repository, remote: &str, filter: &str) -> Result<(), Error> {
+    let mut config = repo.config().context("failed to open repository config")?;
+
+    // libgit2 refuses to open repositories with a format version above 0, but git honors extensions.partialClone in
+    // version 0 repositories anyway.
+    if config.get_string("extensions.partialClone").is_err() {
+      config.set_str("extensions.partialClone", remote)?;
+    }
+    config.set_bool(&format!("remote.{}.promisor", remote), true)?;
+    config.set_str(&format!("remote.{}.partialclonefilter", remote), filter)?;
+    Ok(())
   }
 
   fn open_or_create_bare_repo<T: AsRef<Path>>(path: T) -> Result<git2::Repository, Error> {
@@ -76,61 +142,188 @@ impl Depot {
     }
   }
 
-  fn replace_dir<T: AsRef<Path>>(src: T, dst: T) -> Result<(), Error> {
-    let src: &Path = src.as_ref();
-    let dst: &Path = dst.as_ref();
+  /// Make a repository that borrows objects from a (possibly shallow) objects mirror agree with it about where its
+  /// history is cut off, so that git doesn't go looking for parents that were never fetched.
+  ///
+  /// The repository might have more history than the mirror (e.g. from `git fetch --deepen` in a checkout), so the
+  /// shallow commits of both are merged, keeping only the ones that are still missing parents.
+  fn copy_shallow<T: AsRef<Path>, U: AsRef<Path>>(objects_path: T, git_path: U) -> Result<(), Error> {
+    let src = objects_path.as_ref().join("shallow");
+    let dst = git_path.as_ref().join("shallow");
+    let read_shallow = |path: &Path| -> Result<Vec<String>, Error> {
+      match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(String::from).collect()),
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(format_err!("failed to read {:?}: {}", path, err)),
+      }
+    };
 
-    ensure!(
-      src.exists(),
-      "attempted to replace {:?} with nonexistent directory {:?}",
-      dst,
-      src
-    );
+    let mut candidates = read_shallow(&src)?;
+    candidates.extend(read_shallow(&dst)?);
+    candidates.sort();
+    candidates.dedup();
+
+    let mut shallow = Vec::new();
+    if !candidates.is_empty() {
+      let repo = git2::Repository::open(git_path.as_ref())?;
+      // git2's Odb::exists is always true, so look for the parents' headers instead.
+      let odb = repo.odb()?;
+      for commit in candidates {
+        let parents = match git2::Oid::from_str(&commit).and_then(|oid| repo.find_commit(oid)) {
+          Ok(commit) => commit.parent_ids().collect::<Vec<_>>(),
+          Err(_) => continue,
+        };
+        if parents.iter().any(|&parent| odb.read_header(parent).is_err()) {
+          shallow.push(commit);
+        }
+      }
+    }
+
+    if !shallow.is_empty() {
+      std::fs::write(&dst, shallow.join("\n") + "\n").context(format!("failed to write {:?}", dst))?;
+    } else if dst.exists() {
+      std::fs::remove_file(&dst).context(format!("failed to delete {:?}", dst))?;
+    }
+    Ok(())
+  }
 
-    if dst.exists() {
-      std::fs::remove_dir_all(&dst).context(format!("failed to delete {:?}", dst))?;
+  /// Count the commits in the first-parent history of a ref, which is how deep it is in a shallow repository.
+  fn history_length(repo_path: &Path, reference: &str) -> Option<u32> {
+    let output = std::process::Command::new("git")
+      .arg("-C")
+      .arg(repo_path)
+      .arg("rev-list")
+      .arg("--count")
+      .arg("--first-parent")
+      .arg(reference)
+      .arg("--")
+      .output()
+      .ok()?;
+    if !output.status.success() {
+      return None;
     }
+    std::str::from_utf8(&output.stdout).ok()?.trim().parse().ok()
+  }
 
-    std::fs::create_dir_all(&dst).context(format!("failed to create directory {:?}", dst))?;
+  /// Make the refs under `dst_prefix` in `dst` match the refs under `src_prefix` in `src`.
+  ///
+  /// Each ref is updated atomically by libgit2, so an interrupted update leaves a mix of old and new refs, rather than
+  /// no refs at all. Refs that no longer exist upstream are deleted first, so that a branch can be replaced by a
+  /// directory of the same name (e.g. `foo` by `foo/bar`).
+  fn mirror_refs(
+    src: &git2::Repository,
+    src_prefix: &str,
+    dst: &git2::Repository,
+    dst_prefix: &str,
+  ) -> Result<(), Error> {
+    let collect_refs = |repo: &git2::Repository, prefix: &str| -> Result<BTreeMap<String, git2::Oid>, Error> {
+      let mut refs = BTreeMap::new();
+      for reference in repo.references()? {
+        let reference = reference?;
+        let name = match reference.name() {
+          Some(name) if name.starts_with(prefix) => name[prefix.len()..].to_string(),
+          _ => continue,
+        };
+
+        // Symbolic refs (e.g. refs/remotes/origin/HEAD) aren't mirrored.
+        if let Some(oid) = reference.target() {
+          refs.insert(name, oid);
+        }
+      }
+      Ok(refs)
+    };
+
+    let src_refs = collect_refs(src, src_prefix)?;
+    let dst_refs = collect_refs(dst, dst_prefix)?;
 
-    let entries = std::fs::read_dir(&src).context(format!("failed to read directory {:?}", src))?;
+    for name in dst_refs.keys().filter(|name| !src_refs.contains_key(*name)) {
+      let ref_name = format!("{}{}", dst_prefix, name);
+      dst
+        .find_reference(&ref_name)?
+        .delete()
+        .context(format!("failed to delete {}", ref_name))?;
+    }
 
-    for entry in entries {
-      let entry = entry?;
-      std::fs::copy(entry.path(), dst.join(entry.file_name())).context(format!(
-        "failed to copy {:?} to {:?}",
-        entry.path(),
+    for (name, oid) in &src_refs {
+      if dst_refs.get(name) != Some(oid) {
+        let ref_name = format!("{}{}", dst_prefix, name);
         dst
-      ))?;
+          .reference(&ref_name, *oid, true, "pore: update mirrored ref")
+          .context(format!("failed to update {}", ref_name))?;
+      }
     }
 
     Ok(())
   }
 
+  /// Delete the remote-tracking branches of `remote` that `stale` picks out by their branch names.
+  fn prune_remote_branches(repo: &git2::Repository, remote: &str, stale: impl Fn(&str) -> bool) -> Result<(), Error> {
+    let prefix = format!("refs/remotes/{}/", remote);
+    let mut stale_refs = Vec::new();
+    for reference in repo.references()? {
+      let reference = reference?;
+      let branch = match reference.name() {
+        Some(name) if name.starts_with(&prefix) => &name[prefix.len()..],
+        _ => continue,
+      };
+      if reference.target().is_some() && stale(branch) {
+        stale_refs.push(format!("{}{}", prefix, branch));
+      }
+    }
+
+    for ref_name in stale_refs {
+      repo
+        .find_reference(&ref_name)?
+        .delete()
+        .context(format!("failed to delete {}", ref_name))?;
+    }
+    Ok(())
+  }
+
   pub fn objects_mirror<T: Into<String>>(&self, project: T) -> PathBuf {
     let repo_name: String = project.into() + ".git";
     self.path.join("objects").join(repo_name)
   }
 
+  /// Lock a repository in the depot, which is shared between all of the trees (and pore processes) that use it.
+  fn lock_mirror(path: &Path, description: String) -> Result<FileLock, Error> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    FileLock::acquire(lock_path, &description)
+  }
+
   pub fn refs_mirror<T: AsRef<str>, U: Into<String>>(&self, remote: T, project: U) -> PathBuf {
     let remote: &str = remote.as_ref();
     let repo_name: String = project.into() + ".git";
     self.path.join("refs").join(remote).join(repo_name)
   }
 
+  /// Find the name of the project that a checkout was cloned from, using its alternates.
+  pub fn checkout_project_name<T: AsRef<Path>>(&self, path: T) -> Option<String> {
+    let alternates_path = Depot::git_path(path).join("objects").join("info").join("alternates");
+    let alternates = std::fs::read_to_string(alternates_path).ok()?;
+    let objects_path = Path::new(alternates.lines().next()?);
+    let repo_path = objects_path
+      .strip_prefix(self.path.join("objects"))
+      .ok()?
+      .parent()?
+      .to_str()?;
+    repo_path.strip_suffix(".git").map(String::from)
+  }
+
   pub fn fetch_repo(
     &self,
     remote_config: &config::RemoteConfig,
     project: &str,
-    branch: &str,
-    depth: Option<i32>,
-    progress: Option<&indicatif::ProgressBar>,
+    branches: &[&str],
+    depth: Option<u32>,
+    progress: Option<&mut dyn FnMut(&TransferProgress)>,
   ) -> Result<(), Error> {
     ensure!(!project.starts_with('/'), "invalid project path {}", project);
     ensure!(!project.ends_with('/'), "invalid project path {}", project);
 
-    // TODO: Add locking?
     let objects_path = self.objects_mirror(project);
+    let objects_lock = Depot::lock_mirror(&objects_path, format!("objects mirror of {}", project))?;
     let repo_url = remote_config.url.to_owned() + project + ".git";
 
     let objects_repo = Depot::open_or_create_bare_repo(&objects_path)?;
@@ -144,11 +337,29 @@ impl Depot {
         .context("failed to create remote")?,
     };
 
+    // The objects mirror is shared between trees, so a fetch must never cut off history that another tree asked for,
+    // and `git fetch --depth` moves the shallow boundary to that distance from the new tips, even if it's shallower.
+    // Only a new mirror is fetched with a depth. A shallow mirror is fetched without one, which extends its history
+    // down to its existing boundary, and then deepened if that's not enough. A fetch without a depth unshallows it.
+    let shallow = objects_path.join("shallow").exists();
+    let empty = objects_repo.references()?.next().is_none();
+    let initial_depth = depth.filter(|_| empty);
+    let deepen_to = depth.filter(|_| shallow);
+    let unshallow = shallow && depth.is_none();
+
     // Use libgit2 when we can, because it's significantly faster than shelling out to git.
-    let parsed_url = url::Url::parse(&repo_url)?;
-    let scheme = parsed_url.scheme();
+    let scheme = if util::is_scp_url(&repo_url) {
+      "ssh".to_string()
+    } else {
+      url::Url::parse(&repo_url)?.scheme().to_string()
+    };
     let scheme_supported = scheme == "git" || scheme == "https" || scheme == "http" || scheme == "ssh" || scheme == "";
-    let use_git2 = scheme_supported && depth.is_none();
+    let use_git2 = scheme_supported && initial_depth.is_none() && !shallow && self.filter.is_none();
+
+    // git fetches with the remote's partialclonefilter, so this is all that's needed to fetch without filtered objects.
+    if let Some(filter) = &self.filter {
+      Depot::set_promisor(&objects_repo, &remote_config.name, filter)?;
+    }
 
     if use_git2 {
       let mut fetch_opts = git2::FetchOptions::new();
@@ -157,39 +368,146 @@ impl Depot {
         .update_fetchhead(true)
         .download_tags(git2::AutotagOption::None);
 
+      let mut callbacks = git2::RemoteCallbacks::new();
+      let mut credentials = auth::Credentials::new(&remote_config.auth);
+      callbacks.credentials(move |url, username, allowed| credentials.get(url, username, allowed));
+      if let Some(progress) = progress {
+        callbacks.transfer_progress(move |stats| {
+          progress(&TransferProgress {
+            received_objects: stats.received_objects(),
+            total_objects: stats.total_objects(),
+            received_bytes: stats.received_bytes(),
+          });
+          true
+        });
+      }
+      fetch_opts.remote_callbacks(callbacks);
+
+      // Only the branches that the manifest needs are fetched, so fetching doesn't prune the ones that were deleted
+      // upstream. A branch that's been replaced by a directory of the same name (or vice versa) has to go first, to
+      // make room, and the rest are pruned against the refs that the server advertised for the fetch.
+      let conflicts = |branch: &str| {
+        branches
+          .iter()
+          .any(|&fetched| fetched.starts_with(&format!("{}/", branch)) || branch.starts_with(&format!("{}/", fetched)))
+      };
+      Depot::prune_remote_branches(&objects_repo, &remote_config.name, conflicts)?;
+
       remote
-        .fetch(&[branch], Some(&mut fetch_opts), None)
+        .fetch(branches, Some(&mut fetch_opts), None)
         .context("failed to fetch")?;
+
+      let upstream_branches: HashSet<&str> = remote
+        .list()?
+        .iter()
+        .filter_map(|head| head.name().strip_prefix("refs/heads/"))
+        .collect();
+      Depot::prune_remote_branches(&objects_repo, &remote_config.name, |branch| {
+        !upstream_branches.contains(branch)
+      })?;
     } else {
       let mut cmd = std::process::Command::new("git");
       cmd
         .arg("-C")
         .arg(&objects_path)
         .arg("fetch")
+        .arg("--prune")
         .arg(&remote_config.name)
-        .arg(&branch)
+        .args(branches)
         .arg("--no-tags");
 
-      if let Some(depth) = depth {
+      // git only lists and prunes the refs covered by the refspecs it's given, so fetch with the remote's refspec too,
+      // to prune branches that were deleted upstream (and make room for any that replaced them) in the same fetch.
+      cmd.arg(format!("+refs/heads/*:refs/remotes/{}/*", remote_config.name));
+
+      if let Some(depth) = initial_depth {
         cmd.arg("--depth");
         cmd.arg(depth.to_string());
+      } else if unshallow {
+        cmd.arg("--unshallow");
+      }
+
+      // git only reports progress when stderr is a terminal, unless it's asked to.
+      let mut child = cmd
+        .arg("--progress")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("failed to spawn git fetch")?;
+
+      // Progress updates are terminated by carriage returns, everything else by newlines.
+      let mut stderr = child.stderr.take().unwrap();
+      let mut progress = progress;
+      let mut output = Vec::new();
+      let mut line = Vec::new();
+      let mut buf = [0u8; 4096];
+      loop {
+        let len = stderr.read(&mut buf).context("failed to read output of git fetch")?;
+        if len == 0 {
+          break;
+        }
+
+        for &c in &buf[..len] {
+          if c != b'\r' && c != b'\n' {
+            line.push(c);
+            continue;
+          }
+
+          if let Some(progress) = progress.as_mut() {
+            if let Some(stats) = TransferProgress::parse(&String::from_utf8_lossy(&line)) {
+              progress(&stats);
+            }
+          }
+          if c == b'\n' {
+            output.extend_from_slice(&line);
+            output.push(b'\n');
+          }
+          line.clear();
+        }
+      }
+
+      let status = child.wait().context("failed to wait for git fetch")?;
+      if !status.success() {
+        bail!("git fetch failed: {}", String::from_utf8_lossy(&output));
+      }
+    }
+
+    if let Some(depth) = deepen_to {
+      let mut deepen = 0;
+      for branch in branches {
+        let branch_ref = format!("refs/remotes/{}/{}", remote_config.name, branch);
+        if let Some(length) = Depot::history_length(&objects_path, &branch_ref) {
+          deepen = deepen.max(depth.saturating_sub(length));
+        }
       }
 
-      let git_output = cmd.output().context("failed to spawn git fetch")?;
-      if !git_output.status.success() {
-        bail!("git fetch failed: {}", String::from_utf8_lossy(&git_output.stderr));
+      if deepen > 0 {
+        let git_output = std::process::Command::new("git")
+          .arg("-C")
+          .arg(&objects_path)
+          .arg("fetch")
+          .arg(&remote_config.name)
+          .args(branches)
+          .arg("--no-tags")
+          .arg(format!("--deepen={}", deepen))
+          .output()
+          .context("failed to spawn git fetch")?;
+        if !git_output.status.success() {
+          bail!("git fetch failed: {}", String::from_utf8_lossy(&git_output.stderr));
+        }
       }
     }
 
     let refs_path = self.refs_mirror(&remote_config.name, project);
+    let refs_lock = Depot::lock_mirror(&refs_path, format!("refs mirror of {}", project))?;
     let refs_repo = match git2::Repository::open(&refs_path) {
       Ok(repo) => repo,
       Err(err) => Depot::clone_alternates(&objects_path, &refs_path, true)?,
     };
+    Depot::copy_shallow(&objects_path, &refs_path)?;
 
-    let objects_refs = objects_path.join("refs").join("remotes").join(&remote_config.name);
-    let refs_refs = refs_path.join("refs").join("heads");
-    Depot::replace_dir(&objects_refs, &refs_refs)?;
+    let objects_prefix = format!("refs/remotes/{}/", remote_config.name);
+    Depot::mirror_refs(&objects_repo, &objects_prefix, &refs_repo, "refs/heads/")?;
 
     Ok(())
   }
@@ -204,19 +522,15 @@ impl Depot {
     let path: &Path = path.as_ref();
 
     let repo = Depot::clone_alternates(self.objects_mirror(project), path.to_path_buf(), false)?;
-    repo
-      .remote(
-        &remote_config.name,
-        self.refs_mirror(&remote_config.name, project).to_str().unwrap(),
-      )
-      .context("failed to create remote")?;
-    repo
-      .remote_set_pushurl(&remote_config.name, Some(&format!("{}{}", remote_config.url, project)))
-      .context("failed to set remote pushurl")?;
-
     self.update_remote_refs(&remote_config, project, &path)?;
 
     let head = util::parse_revision(&repo, &remote_config.name, &branch)?;
+    self.fetch_checkout_objects(
+      remote_config,
+      project,
+      self.objects_mirror(project),
+      &[head.peel_to_commit()?.id()],
+    )?;
     repo
       .checkout_tree(&head, None)
       .context(format!("failed to checkout HEAD at {:?}", repo.path()))?;
@@ -234,15 +548,411 @@ impl Depot {
   ) -> Result<(), Error> {
     let path: &Path = path.as_ref();
 
-    let mirror_refs = self
-      .refs_mirror(&remote_config.name, project)
-      .join("refs")
-      .join("heads");
-    let repo_refs = Depot::git_path(path)
-      .join("refs")
-      .join("remotes")
-      .join(&remote_config.name);
+    let refs_path = self.refs_mirror(&remote_config.name, project);
+    let refs_lock = Depot::lock_mirror(&refs_path, format!("refs mirror of {}", project))?;
+    let refs_repo =
+      git2::Repository::open_bare(&refs_path).context(format!("failed to open repository {:?}", refs_path))?;
+    let repo = git2::Repository::open(path).context(format!("failed to open repository {:?}", path))?;
+    self.update_checkout_remote(&repo, remote_config, project)?;
+
+    Depot::copy_shallow(self.objects_mirror(project), Depot::git_path(path))?;
+    if let Some(filter) = &self.filter {
+      // Objects that pore needs are fetched into the objects mirror by fetch_checkout_objects, and git fetches anything
+      // else (e.g. old blobs for git log -p) through the objects mirror too, so that they're shared between trees.
+      // upload-pack refuses to fetch objects that it's missing, so it's wrapped by `pore promisor-upload-pack`, which
+      // fetches them into the mirror first.
+      let exe = std::env::current_exe().context("failed to find the path of pore")?;
+      let upload_pack = format!(
+        "{} promisor-upload-pack {}",
+        util::shell_quote(&exe.to_string_lossy()),
+        util::shell_quote(&remote_config.name)
+      );
+
+      // The remote is only used for fetching missing objects, so it's set up without a fetch refspec.
+      let mut config = repo.config()?;
+      config
+        .set_str(
+          &format!("remote.{}.url", PROMISOR_REMOTE),
+          self.objects_mirror(project).to_str().unwrap(),
+        )
+        .context("failed to create promisor remote")?;
+      config
+        .set_str(&format!("remote.{}.uploadpack", PROMISOR_REMOTE), &upload_pack)
+        .context("failed to create promisor remote")?;
+      Depot::set_promisor(&repo, PROMISOR_REMOTE, filter)?;
+    }
 
-    Depot::replace_dir(&mirror_refs, &repo_refs)
+    let repo_prefix = format!("refs/remotes/{}/", remote_config.name);
+    Depot::mirror_refs(&refs_repo, "refs/heads/", &repo, &repo_prefix)
+  }
+
+  /// Point a checkout's remote at the refs mirror, with a pushurl for uploading to the remote.
+  ///
+  /// Checkouts used to be cloned with the tree's remote name instead of the project's remote in the manifest, so if
+  /// the remote is missing, rename whichever remote points into the depot's refs mirrors, which also moves its remote
+  /// refs and the upstreams of branches that track it.
+  fn update_checkout_remote(
+    &self,
+    repo: &git2::Repository,
+    remote_config: &config::RemoteConfig,
+    project: &str,
+  ) -> Result<(), Error> {
+    let name = &remote_config.name;
+    if repo.find_remote(name).is_err() {
+      let refs_root = self.path.join("refs");
+      let suffix = format!("/{}.git", project);
+      let previous = repo
+        .remotes()?
+        .iter()
+        .flatten()
+        .filter(|remote| remote != name)
+        .find(|remote| {
+          repo.find_remote(remote).ok().is_some_and(|remote| {
+            remote
+              .url()
+              .is_some_and(|url| Path::new(url).starts_with(&refs_root) && url.ends_with(&suffix))
+          })
+        })
+        .map(String::from);
+
+      match previous {
+        Some(previous) => {
+          repo
+            .remote_rename(&previous, name)
+            .context(format!("failed to rename remote {} to {}", previous, name))?;
+        }
+        None => {
+          repo
+            .remote(name, self.refs_mirror(name, project).to_str().unwrap())
+            .context("failed to create remote")?;
+        }
+      }
+    }
+
+    repo
+      .remote_set_url(name, self.refs_mirror(name, project).to_str().unwrap())
+      .context("failed to set remote url")?;
+    repo
+      .remote_set_pushurl(name, Some(&format!("{}{}", remote_config.url, project)))
+      .context("failed to set remote pushurl")?;
+    Ok(())
+  }
+
+  /// Make sure that the objects mirror has everything needed to check out commits in a repository that uses it.
+  ///
+  /// libgit2 doesn't know how to fetch missing objects from a partial clone, so fetch every object that's missing
+  /// from the commits' trees with a single git fetch, the way git checkout does. `repo_path` can be the objects mirror
+  /// itself, or a checkout that borrows objects from it, for commits that only exist locally.
+  pub fn fetch_checkout_objects<T: AsRef<Path>>(
+    &self,
+    remote_config: &config::RemoteConfig,
+    project: &str,
+    repo_path: T,
+    commits: &[git2::Oid],
+  ) -> Result<(), Error> {
+    if self.filter.is_none() || commits.is_empty() {
+      return Ok(());
+    }
+
+    let rev_list = std::process::Command::new("git")
+      .arg("-C")
+      .arg(repo_path.as_ref())
+      .arg("rev-list")
+      .arg("--objects")
+      .arg("--no-walk")
+      .arg("--missing=print")
+      .args(commits.iter().map(|commit| commit.to_string()))
+      .output()
+      .context("failed to spawn git rev-list")?;
+    if !rev_list.status.success() {
+      bail!("git rev-list failed: {}", String::from_utf8_lossy(&rev_list.stderr));
+    }
+
+    let missing: Vec<&str> = std::str::from_utf8(&rev_list.stdout)?
+      .lines()
+      .filter_map(|line| line.strip_prefix('?'))
+      .collect();
+    let objects_path = self.objects_mirror(project);
+    Depot::fetch_missing_objects(&objects_path, &remote_config.name, &missing)
+  }
+
+  /// Fetch specific objects into an objects mirror that's a partial clone.
+  fn fetch_missing_objects(objects_path: &Path, remote: &str, objects: &[&str]) -> Result<(), Error> {
+    if objects.is_empty() {
+      return Ok(());
+    }
+
+    let description = format!("objects mirror {:?}", objects_path);
+    let objects_lock = Depot::lock_mirror(objects_path, description)?;
+    let mut fetch = std::process::Command::new("git")
+      .arg("-C")
+      .arg(objects_path)
+      .arg("-c")
+      .arg("fetch.negotiationAlgorithm=noop")
+      .arg("fetch")
+      .arg(remote)
+      .arg("--no-tags")
+      .arg("--no-write-fetch-head")
+      .arg("--recurse-submodules=no")
+      .arg("--stdin")
+      .stdin(std::process::Stdio::piped())
+      .stdout(std::process::Stdio::null())
+      .stderr(std::process::Stdio::piped())
+      .spawn()
+      .context("failed to spawn git fetch")?;
+    fetch
+      .stdin
+      .take()
+      .unwrap()
+      .write_all((objects.join("\n") + "\n").as_bytes())?;
+    let git_output = fetch.wait_with_output().context("failed to wait for git fetch")?;
+    if !git_output.status.success() {
+      bail!("git fetch failed: {}", String::from_utf8_lossy(&git_output.stderr));
+    }
+    Ok(())
+  }
+
+  /// Serve a checkout's on-demand fetch of missing objects from an objects mirror, as git upload-pack.
+  ///
+  /// upload-pack won't fetch objects that a partial clone is missing, so this sits between git and upload-pack,
+  /// fetching the objects in each batch of wants into the mirror before passing the request along.
+  pub fn promisor_upload_pack(remote: &str, objects_path: &Path) -> Result<i32, Error> {
+    let mut upload_pack = std::process::Command::new("git")
+      .arg("-c")
+      .arg("uploadpack.allowAnySHA1InWant=true")
+      .arg("-c")
+      .arg("uploadpack.allowFilter=true")
+      .arg("upload-pack")
+      .arg(objects_path)
+      .stdin(std::process::Stdio::piped())
+      .spawn()
+      .context("failed to spawn git upload-pack")?;
+    let mut upload_pack_input = upload_pack.stdin.take().unwrap();
+
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let mut wants = Vec::new();
+    let mut pending = Vec::new();
+    let mut protocol_v2 = false;
+    loop {
+      // pkt-lines are prefixed with their length (including the prefix) in hex, with special values below 4.
+      let mut packet = vec![0u8; 4];
+      match input.read_exact(&mut packet) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+        Err(err) => return Err(err.into()),
+      }
+      let length = usize::from_str_radix(std::str::from_utf8(&packet)?, 16)?;
+      if length > 4 {
+        packet.resize(length, 0);
+        input.read_exact(&mut packet[4..])?;
+      }
+
+      if packet[4..].starts_with(b"command=") {
+        protocol_v2 = true;
+      }
+
+      // upload-pack rejects wants that it doesn't have as soon as the list of wants ends, so hold them back until
+      // the next packet that isn't a want, and fetch them into the mirror before passing them along. git fetch skips
+      // objects that the mirror already has.
+      if let Some(want) = packet[4..].strip_prefix(b"want ") {
+        let oid = String::from_utf8_lossy(&want[..std::cmp::min(40, want.len())]).into_owned();
+        wants.push(oid);
+        pending.extend_from_slice(&packet);
+        continue;
+      }
+
+      if !wants.is_empty() {
+        let objects: Vec<&str> = wants.iter().map(String::as_str).collect();
+        Depot::fetch_missing_objects(objects_path, remote, &objects)?;
+
+        // The checkout can use the objects through its alternates now, so claim to have them, to get an empty pack
+        // instead of another copy. Protocol v0 sends haves in a separate round, so that only gets the copy.
+        if protocol_v2 {
+          for want in &wants {
+            let have = format!("have {}\n", want);
+            pending.extend_from_slice(format!("{:04x}{}", have.len() + 4, have).as_bytes());
+          }
+        }
+        wants.clear();
+      }
+      pending.extend_from_slice(&packet);
+      upload_pack_input.write_all(&pending)?;
+      upload_pack_input.flush()?;
+      pending.clear();
+    }
+
+    upload_pack_input.write_all(&pending)?;
+    drop(upload_pack_input);
+    let status = upload_pack.wait().context("failed to wait for git upload-pack")?;
+    Ok(status.code().unwrap_or(1))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testutil::{self, TestServer};
+
+  /// List the refs under `prefix` in a repository, and what they point to.
+  fn refs(path: &Path, prefix: &str) -> BTreeMap<String, String> {
+    testutil::git(path, &["for-each-ref", "--format=%(refname) %(objectname)", prefix])
+      .lines()
+      .map(|line| {
+        let mut words = line.split(' ');
+        let name = words.next().unwrap()[prefix.len()..].to_string();
+        (name, words.next().unwrap().to_string())
+      })
+      .collect()
+  }
+
+  fn ref_names(path: &Path, prefix: &str) -> Vec<String> {
+    refs(path, prefix).into_keys().collect()
+  }
+
+  #[test]
+  fn mirror_refs() {
+    let dir = tempfile::tempdir().unwrap();
+    let src_path = dir.path().join("src");
+    let dst_path = dir.path().join("dst.git");
+    std::fs::create_dir_all(&src_path).unwrap();
+    std::fs::create_dir_all(&dst_path).unwrap();
+    testutil::git(&src_path, &["init", "-q"]);
+    testutil::git(&dst_path, &["init", "-q", "--bare"]);
+    let alternates = src_path.join(".git").join("objects");
+    std::fs::write(dst_path.join("objects/info/alternates"), alternates.to_str().unwrap()).unwrap();
+
+    testutil::git(&src_path, &["checkout", "-q", "-b", "master"]);
+    testutil::commit_file(&src_path, "README", "1");
+    for branch in &["foo", "topic/one", "topic/two/deep"] {
+      testutil::git(&src_path, &["branch", branch]);
+    }
+
+    // Mirror a mix of packed and loose refs.
+    testutil::git(&src_path, &["pack-refs", "--all"]);
+    testutil::commit_file(&src_path, "README", "2");
+    testutil::git(&src_path, &["branch", "loose"]);
+
+    let src = git2::Repository::open(&src_path).unwrap();
+    let dst = git2::Repository::open(&dst_path).unwrap();
+    Depot::mirror_refs(&src, "refs/heads/", &dst, "refs/mirror/").unwrap();
+    assert_eq!(refs(&src_path, "refs/heads/"), refs(&dst_path, "refs/mirror/"));
+    assert_eq!(
+      ref_names(&dst_path, "refs/mirror/"),
+      vec!["foo", "loose", "master", "topic/one", "topic/two/deep"]
+    );
+
+    // Delete a packed branch, replace a branch with a directory of the same name, and move another.
+    testutil::git(&src_path, &["branch", "-D", "topic/one", "foo"]);
+    testutil::git(&src_path, &["branch", "foo/bar"]);
+    testutil::commit_file(&src_path, "README", "3");
+    testutil::git(&src_path, &["branch", "-f", "topic/two/deep"]);
+
+    Depot::mirror_refs(&src, "refs/heads/", &dst, "refs/mirror/").unwrap();
+    assert_eq!(refs(&src_path, "refs/heads/"), refs(&dst_path, "refs/mirror/"));
+    assert_eq!(
+      ref_names(&dst_path, "refs/mirror/"),
+      vec!["foo/bar", "loose", "master", "topic/two/deep"]
+    );
+  }
+
+  #[test]
+  fn fetch_prunes_deleted_branches() {
+    let server = TestServer::new(&["platform/a"]);
+    let work = server.dir.path().join("work").join("platform/a");
+    let upstream = server.repo_path("platform/a");
+    for branch in &["feature", "foo"] {
+      testutil::git(
+        &work,
+        &["push", "-q", upstream.to_str().unwrap(), &format!("master:{}", branch)],
+      );
+    }
+
+    let config = server.config();
+    let remote = config.find_remote("test").unwrap();
+    let depot = server.depot();
+    depot
+      .fetch_repo(&remote, "platform/a", &["master", "feature", "foo"], None, None)
+      .unwrap();
+
+    let objects_path = depot.objects_mirror("platform/a");
+    let refs_path = depot.refs_mirror("test", "platform/a");
+    assert_eq!(
+      ref_names(&objects_path, "refs/remotes/test/"),
+      vec!["feature", "foo", "master"]
+    );
+    assert_eq!(ref_names(&refs_path, "refs/heads/"), vec!["feature", "foo", "master"]);
+
+    // Deleted branches are pruned even though they aren't fetched, and a branch can be replaced by a directory.
+    testutil::git(&upstream, &["branch", "-D", "feature", "foo"]);
+    testutil::git(&work, &["push", "-q", upstream.to_str().unwrap(), "master:foo/bar"]);
+    depot
+      .fetch_repo(&remote, "platform/a", &["master", "foo/bar"], None, None)
+      .unwrap();
+
+    assert_eq!(
+      ref_names(&objects_path, "refs/remotes/test/"),
+      vec!["foo/bar", "master"]
+    );
+    assert_eq!(ref_names(&refs_path, "refs/heads/"), vec!["foo/bar", "master"]);
+  }
+
+  #[test]
+  fn shallow_mirror_shared_between_trees() {
+    let server = TestServer::new(&["platform/a"]);
+    let work = server.dir.path().join("work").join("platform/a");
+    let upstream = server.repo_path("platform/a");
+    let push = || testutil::git(&work, &["push", "-q", upstream.to_str().unwrap(), "master"]);
+    for i in 0..5 {
+      testutil::commit_file(&work, "README", &i.to_string());
+    }
+    push();
+
+    let config = server.config();
+    let remote = config.find_remote("test").unwrap();
+    let depot = server.depot();
+    let objects_path = depot.objects_mirror("platform/a");
+    let length = |path: &Path| Depot::history_length(path, "HEAD");
+    let mirror_length = || Depot::history_length(&objects_path, "refs/remotes/test/master");
+
+    depot
+      .fetch_repo(&remote, "platform/a", &["master"], Some(4), None)
+      .unwrap();
+    assert_eq!(mirror_length(), Some(4));
+
+    let checkout = server.dir.path().join("checkout");
+    depot.clone_repo(&remote, "platform/a", "master", &checkout).unwrap();
+    assert_eq!(length(&checkout), Some(4));
+
+    // A tree that wants less history doesn't take it away from the others.
+    depot
+      .fetch_repo(&remote, "platform/a", &["master"], Some(1), None)
+      .unwrap();
+    assert_eq!(mirror_length(), Some(4));
+
+    testutil::commit_file(&work, "README", "5");
+    testutil::commit_file(&work, "README", "6");
+    push();
+    depot
+      .fetch_repo(&remote, "platform/a", &["master"], Some(1), None)
+      .unwrap();
+    assert_eq!(mirror_length(), Some(6));
+
+    // A tree that wants more history deepens the mirror.
+    depot
+      .fetch_repo(&remote, "platform/a", &["master"], Some(7), None)
+      .unwrap();
+    assert_eq!(mirror_length(), Some(7));
+
+    // A checkout that was deepened by hand keeps its history, even past the mirror's (which stops short of the root).
+    testutil::git(
+      &checkout,
+      &["fetch", "-q", "--deepen=3", upstream.to_str().unwrap(), "master"],
+    );
+    depot
+      .fetch_repo(&remote, "platform/a", &["master"], Some(1), None)
+      .unwrap();
+    depot.update_remote_refs(&remote, "platform/a", &checkout).unwrap();
+    assert_eq!(length(&checkout), Some(6));
   }
 }
@@ -0,0 +1,186 @@
+/*
+ * Copyright (C) 2019 Josh Gao
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+
+static WAIT: AtomicBool = AtomicBool::new(false);
+
+/// Wait for locks held by other processes to be released, instead of failing immediately.
+pub fn set_wait(wait: bool) {
+  WAIT.store(wait, Ordering::SeqCst);
+}
+
+/// The process that holds a lock, as recorded in its lock file.
+#[derive(Debug, PartialEq)]
+struct Owner {
+  pid: i32,
+  hostname: String,
+}
+
+impl Owner {
+  fn current() -> Owner {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let hostname = if rc == 0 {
+      let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+      String::from_utf8_lossy(&buf[..len]).into_owned()
+    } else {
+      "localhost".into()
+    };
+
+    Owner {
+      pid: std::process::id() as i32,
+      hostname,
+    }
+  }
+
+  fn parse(contents: &str) -> Option<Owner> {
+    let mut words = contents.split_whitespace();
+    let pid = words.next()?.parse().ok()?;
+    let hostname = words.next()?.to_string();
+    Some(Owner { pid, hostname })
+  }
+}
+
+impl fmt::Display for Owner {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "pid {} on {}", self.pid, self.hostname)
+  }
+}
+
+/// An advisory lock, held for as long as the FileLock is alive.
+///
+/// The lock is an flock on a lock file that's left in place, so the kernel releases it when its owner exits, even if
+/// it dies without cleaning up. The lock file contains the pid and hostname of its owner, so that we can say who holds
+/// it.
+#[derive(Debug)]
+pub struct FileLock {
+  // Closing the file releases the lock.
+  _file: std::fs::File,
+}
+
+impl FileLock {
+  pub fn acquire<T: Into<PathBuf>>(path: T, description: &str) -> Result<FileLock, Error> {
+    let path = path.into();
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent).context(format!("failed to create directory {:?}", parent))?;
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)
+      .context(format!("failed to open lock file {:?}", path))?;
+
+    let owner = Owner::current();
+    let mut waiting = false;
+    loop {
+      let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+      if rc == 0 {
+        file.set_len(0).context(format!("failed to truncate {:?}", path))?;
+        file
+          .seek(SeekFrom::Start(0))
+          .context(format!("failed to seek {:?}", path))?;
+        writeln!(file, "{} {}", owner.pid, owner.hostname).context(format!("failed to write {:?}", path))?;
+        return Ok(FileLock { _file: file });
+      }
+
+      let err = std::io::Error::last_os_error();
+      match err.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => {}
+        Some(libc::EINTR) => continue,
+        _ => bail!("failed to lock {:?}: {}", path, err),
+      }
+
+      // The owner might not have written its pid yet.
+      let holder = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| Owner::parse(&contents))
+        .map(|holder| holder.to_string())
+        .unwrap_or_else(|| "an unknown process".into());
+
+      // Other threads in this process will release their locks soon enough.
+      let ours = holder == owner.to_string();
+      if !ours {
+        ensure!(
+          WAIT.load(Ordering::SeqCst),
+          "{} is locked by {} ({:?}), use --wait to wait for it",
+          description,
+          holder,
+          path
+        );
+
+        if !waiting {
+          eprintln!("waiting for {} to release its lock on {}", holder, description);
+          waiting = true;
+        }
+      }
+
+      std::thread::sleep(Duration::from_millis(100));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn leftover_lock_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lock");
+
+    // A process that died while holding the lock leaves its lock file behind, which doesn't matter.
+    std::fs::write(&path, "1 some-other-host\n").unwrap();
+    let lock = FileLock::acquire(&path, "test").unwrap();
+    let owner = Owner::parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(owner, Owner::current());
+
+    drop(lock);
+    assert!(path.exists());
+    FileLock::acquire(&path, "test").unwrap();
+  }
+
+  #[test]
+  fn wait_for_other_thread() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lock");
+
+    let lock = FileLock::acquire(&path, "test").unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let thread_path = path.clone();
+    let thread = std::thread::spawn(move || {
+      let lock = FileLock::acquire(&thread_path, "test").unwrap();
+      tx.send(()).unwrap();
+      drop(lock);
+    });
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(rx.try_recv().is_err());
+    drop(lock);
+    rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    thread.join().unwrap();
+  }
+}
@@ -57,20 +57,25 @@ macro_rules! fatal {
   }}
 }
 
+mod auth;
 mod config;
 mod depot;
 mod hooks;
+mod lock;
 mod manifest;
 mod tree;
 mod util;
 
+#[cfg(test)]
+mod testutil;
+
 use config::Config;
+use depot::Depot;
 use manifest::Manifest;
-use tree::{CheckoutType, FetchType, GroupFilter, Tree};
-
-fn unimplemented_subcommand(function: &str) -> ! {
-  fatal!("unimplemented subcommand {}", function);
-}
+use tree::{
+  CheckoutType, FetchType, FileState, ForallOptions, GroupFilter, ManifestSource, ProjectStatus, StrayFile, Tree,
+  UploadOptions,
+};
 
 fn parse_target(target: &str) -> Result<(String, String), Error> {
   let vec: Vec<&str> = target.split('/').collect();
@@ -83,11 +88,28 @@ fn parse_target(target: &str) -> Result<(String, String), Error> {
   }
 }
 
+fn parse_group_filters(group_filters: Option<&str>) -> Vec<GroupFilter> {
+  group_filters
+    .map(|s| {
+      s.split(',')
+        .map(|group| {
+          if group.starts_with('-') {
+            GroupFilter::Exclude(group[1..].to_string())
+          } else {
+            GroupFilter::Include(group.to_string())
+          }
+        })
+        .collect()
+    })
+    .unwrap_or_else(Vec::new)
+}
+
 fn cmd_clone(
   config: Config,
   mut pool: &mut ThreadPool,
   target: &str,
   directory: Option<&str>,
+  manifest_source: ManifestSource,
   group_filters: Option<&str>,
   fetch: bool,
 ) -> Result<i32, Error> {
@@ -100,22 +122,16 @@ fn cmd_clone(
     bail!("failed to create tree root {:?}: {}", tree_root, err);
   }
 
-  let group_filters = group_filters
-    .map(|s| {
-      s.split(',')
-        .map(|group| {
-          if group.starts_with('-') {
-            GroupFilter::Exclude(group[1..].to_string())
-          } else {
-            GroupFilter::Include(group.to_string())
-          }
-        })
-        .collect()
-    })
-    .unwrap_or_else(Vec::new);
-
-  // TODO: Add locking?
-  let mut tree = Tree::construct(&depot, &tree_root, &remote_config, &branch, group_filters, fetch)?;
+  let group_filters = parse_group_filters(group_filters);
+  let mut tree = Tree::construct(
+    &depot,
+    &tree_root,
+    &remote_config,
+    &branch,
+    manifest_source,
+    group_filters,
+    fetch,
+  )?;
   let fetch_type = if fetch {
     // We just fetched the manifest.
     FetchType::FetchExceptManifest
@@ -123,26 +139,58 @@ fn cmd_clone(
     FetchType::NoFetch
   };
 
-  tree.sync(&config, &mut pool, &depot, None, fetch_type, CheckoutType::Checkout)
+  tree.sync(
+    &config,
+    &mut pool,
+    &depot,
+    None,
+    None,
+    fetch_type,
+    CheckoutType::Checkout,
+    false,
+  )
 }
 
+#[allow(clippy::too_many_arguments)]
 fn cmd_sync(
   config: Config,
   mut pool: &mut ThreadPool,
   tree: &mut Tree,
   sync_under: Option<Vec<&str>>,
+  manifest_file: Option<&str>,
   fetch: FetchType,
   checkout: CheckoutType,
+  prune_removed: bool,
 ) -> Result<i32, Error> {
   let remote_config = config.find_remote(&tree.config.remote)?;
   let depot = config.find_depot(&remote_config.depot)?;
-  tree.sync(&config, &mut pool, &depot, sync_under, fetch, checkout)
+  let manifest_file = manifest_file.map(Path::new);
+  tree.sync(
+    &config,
+    &mut pool,
+    &depot,
+    sync_under,
+    manifest_file,
+    fetch,
+    checkout,
+    prune_removed,
+  )
 }
 
 fn cmd_start(config: Config, tree: &mut Tree, branch_name: &str, directory: &Path) -> Result<i32, Error> {
   let remote_config = config.find_remote(&tree.config.remote)?;
   let depot = config.find_depot(&remote_config.depot)?;
-  tree.start(&config, &depot, &remote_config, branch_name, &directory)
+  tree.start(&config, &depot, branch_name, &directory)
+}
+
+fn cmd_upload(
+  config: Config,
+  pool: &mut ThreadPool,
+  tree: &mut Tree,
+  upload_under: Option<Vec<&str>>,
+  options: &UploadOptions,
+) -> Result<i32, Error> {
+  tree.upload(&config, pool, upload_under, options)
 }
 
 fn cmd_prune(config: Config, mut pool: &mut ThreadPool, tree: &mut Tree) -> Result<i32, Error> {
@@ -151,14 +199,150 @@ fn cmd_prune(config: Config, mut pool: &mut ThreadPool, tree: &mut Tree) -> Resu
   tree.prune(&config, &mut pool, &depot)
 }
 
+fn cmd_status(
+  config: Config,
+  pool: &mut ThreadPool,
+  tree: &Tree,
+  status_under: Option<Vec<&str>>,
+  format: &str,
+) -> Result<i32, Error> {
+  let statuses = tree.status(&config, pool, status_under)?;
+
+  let dirty = statuses.iter().any(ProjectStatus::is_dirty);
+
+  match format {
+    "json" => {
+      serde_json::to_writer_pretty(std::io::stdout(), &statuses).context("failed to serialize status")?;
+      println!();
+    }
+    "porcelain" => print_status_porcelain(&statuses),
+    _ => print_status(&statuses),
+  }
+
+  Ok(if dirty { 1 } else { 0 })
+}
+
+fn cmd_status_stray(config: Config, tree: &Tree, status_under: Option<Vec<&str>>, format: &str) -> Result<i32, Error> {
+  let stray = tree.stray_files(&config, status_under)?;
+  match format {
+    "json" => {
+      serde_json::to_writer_pretty(std::io::stdout(), &stray).context("failed to serialize stray files")?;
+      println!();
+    }
+    "porcelain" => {
+      for file in &stray {
+        let kind = if file.directory { "directory" } else { "file" };
+        println!("stray\t{}\t{}", kind, file.path);
+      }
+    }
+    _ => print_stray(&stray),
+  }
+
+  Ok(if stray.is_empty() { 0 } else { 1 })
+}
+
+fn print_stray(stray: &[StrayFile]) {
+  if stray.is_empty() {
+    return;
+  }
+
+  println!("{}", console::style("files outside of any project:").bold());
+  for file in stray {
+    let suffix = if file.directory { "/" } else { "" };
+    println!("{}", console::style(format!("  {}{}", file.path, suffix)).red());
+  }
+}
+
+fn print_status(statuses: &[ProjectStatus]) {
+  // Projects that are on a detached HEAD with no changes are uninteresting, because that's how they're synced.
+  for project_status in statuses.iter().filter(|status| status.is_dirty()) {
+    let project_line = console::style(format!("project {:64}", project_status.project_path)).bold();
+    let branch = match &project_status.branch {
+      Some(branch) => console::style(format!("branch {}", branch)).bold(),
+      None => console::style("(*** NO BRANCH ***)".to_string()).red(),
+    };
+
+    let mut counts = Vec::new();
+    if project_status.ahead > 0 {
+      counts.push(format!("ahead {}", project_status.ahead));
+    }
+    if project_status.behind > 0 {
+      counts.push(format!("behind {}", project_status.behind));
+    }
+
+    match &project_status.upstream {
+      Some(upstream) if !counts.is_empty() => {
+        let tracking = console::style(format!(" [{}: {}]", upstream, counts.join(", "))).yellow();
+        println!("{}{}{}", project_line, branch, tracking);
+      }
+      _ => println!("{}{}", project_line, branch),
+    }
+
+    for file in &project_status.files {
+      let index = file.index.to_char().to_uppercase().to_string();
+      let worktree = file.worktree.to_char();
+      let mut line = console::style(format!(" {}{}     {}", index, worktree, file.filename));
+      if file.worktree != FileState::Unchanged {
+        line = line.red();
+      } else {
+        line = line.green();
+      }
+
+      println!("{}", line)
+    }
+  }
+}
+
+/// Print status in a format that's stable across versions, for use by scripts.
+///
+/// Each project is printed on a line of tab separated fields, as `project <path> branch <branch>` or
+/// `project <path> detached`, followed by `upstream <revision> <ahead> <behind>` if the manifest revision was found.
+/// After that comes a line for each changed file, as `<index><worktree> <filename>`.
+fn print_status_porcelain(statuses: &[ProjectStatus]) {
+  for project_status in statuses {
+    let mut line = match &project_status.branch {
+      Some(branch) => format!("project\t{}\tbranch\t{}", project_status.project_path, branch),
+      None => format!("project\t{}\tdetached", project_status.project_path),
+    };
+
+    if let Some(upstream) = &project_status.upstream {
+      line += &format!(
+        "\tupstream\t{}\t{}\t{}",
+        upstream, project_status.ahead, project_status.behind
+      );
+    }
+    println!("{}", line);
+
+    for file in &project_status.files {
+      println!("{}{}\t{}", file.index.to_char(), file.worktree.to_char(), file.filename);
+    }
+  }
+}
+
+fn cmd_manifest(config: Config, tree: &Tree, pinned: bool, output: Option<&str>) -> Result<i32, Error> {
+  let remote_config = config.find_remote(&tree.config.remote)?;
+  let depot = config.find_depot(&remote_config.depot)?;
+  let manifest = tree.manifest(&config, &depot, pinned)?;
+  match output {
+    Some(path) => {
+      let file = std::fs::File::create(path).context(format!("failed to create {}", path))?;
+      manifest.serialize(file)?;
+    }
+
+    None => manifest.serialize(std::io::stdout())?,
+  }
+  Ok(0)
+}
+
 fn cmd_forall(
   config: Config,
   mut pool: &mut ThreadPool,
   tree: &mut Tree,
   forall_under: Option<Vec<&str>>,
+  options: &ForallOptions,
   command: &str,
 ) -> Result<i32, Error> {
-  tree.forall(&config, &mut pool, forall_under, command)
+  tree.forall(&config, &mut pool, forall_under, options, command)
 }
 
 fn main() {
@@ -178,6 +362,7 @@ fn main() {
     (@arg CWD: -C +takes_value "run as if started in PATH instead of the current working directory")
     (@arg JOBS: -j +takes_value +global "number of jobs to use at a time, defaults to CPU_COUNT.")
     (@arg VERBOSE: -v ... "increase verbosity")
+    (@arg WAIT: --wait +global "wait for locks held by other pore processes, instead of failing")
 
     (@subcommand init =>
       (about: "checkout a new tree into the current directory")
@@ -190,6 +375,14 @@ fn main() {
          groups can be prepended with - to specifically exclude them"
       )
       (@arg LOCAL: -l "don't fetch; use only the local cache")
+      (@arg MANIFEST_FILE: -m --("manifest-name") +takes_value
+        "manifest file to use, relative to the root of the manifest repository\n\
+         defaults to default.xml if unspecified"
+      )
+      (@arg MANIFEST_PATH: --("manifest-file") +takes_value conflicts_with[MANIFEST_FILE]
+        "use the manifest at the given path (e.g. from pore manifest --pinned) instead of one from the manifest\n\
+         repository. it's copied into the tree and used by every subsequent sync"
+      )
     )
     (@subcommand clone =>
       (about: "checkout a new tree into a new directory")
@@ -206,6 +399,14 @@ fn main() {
          groups can be prepended with - to specifically exclude them"
       )
       (@arg LOCAL: -l "don't fetch; use only the local cache")
+      (@arg MANIFEST_FILE: -m --("manifest-name") +takes_value
+        "manifest file to use, relative to the root of the manifest repository\n\
+         defaults to default.xml if unspecified"
+      )
+      (@arg MANIFEST_PATH: --("manifest-file") +takes_value conflicts_with[MANIFEST_FILE]
+        "use the manifest at the given path (e.g. from pore manifest --pinned) instead of one from the manifest\n\
+         repository. it's copied into the tree and used by every subsequent sync"
+      )
     )
     (@subcommand fetch =>
       (about: "fetch a tree's repositories without checking out")
@@ -217,6 +418,14 @@ fn main() {
     (@subcommand sync =>
       (about: "fetch and checkout a tree's repositories")
       (@arg LOCAL: -l "don't fetch; use only the local cache")
+      (@arg MANIFEST_FILE: -m --("manifest-file") +takes_value
+        "sync to the manifest at the given path, instead of the tree's manifest\n\
+         local manifests are not applied, and the tree's manifest is left unchanged"
+      )
+      (@arg PRUNE_REMOVED: --("prune-removed")
+        "delete projects that were removed from the manifest\n\
+         projects with local branches, uncommitted changes, or unpushed commits are kept"
+      )
       (@arg PATH: ...
         "path(s) beneath which repositories are synced\n\
          defaults to all repositories in the tree if unspecified"
@@ -228,6 +437,19 @@ fn main() {
     )
     (@subcommand upload =>
       (about: "upload patches to Gerrit")
+      (@arg PATH: ...
+        "path(s) beneath which to upload branches\n\
+         defaults to all repositories in the tree if unspecified"
+      )
+      (@arg TOPIC: -t --topic +takes_value "topic to set on the uploaded changes")
+      (@arg REVIEWERS: --re +takes_value "comma delimited list of reviewers to add")
+      (@arg CC: --cc +takes_value "comma delimited list of users to CC")
+      (@arg HASHTAGS: --hashtag +takes_value "comma delimited list of hashtags to add")
+      (@arg PRIVATE: --private "upload the changes as private")
+      (@arg WIP: --wip "upload the changes as work in progress")
+      (@arg DRY_RUN: -n --("dry-run") "show what would be uploaded, without uploading")
+      (@arg YES: -y --yes "upload without asking for confirmation")
+      (@arg NO_VERIFY: --("no-verify") "don't run the manifest's pre-upload hook")
     )
     (@subcommand prune =>
       (about: "prune branches that have been merged")
@@ -238,6 +460,18 @@ fn main() {
         "path(s) beneath which to calculate status\n\
          defaults to all repositories in the tree if unspecified"
       )
+      (@arg FORMAT: --format +takes_value possible_value[text json porcelain] default_value[text]
+        "output format"
+      )
+      (@arg STRAY: --stray
+        "list files and directories in the tree that don't belong to any project, instead of project status\n\
+         paths in the config's stray_ignore list aren't reported"
+      )
+    )
+    (@subcommand manifest =>
+      (about: "print the tree's manifest, with local manifests applied")
+      (@arg PINNED: -r --pinned "pin each project to the commit that is currently checked out")
+      (@arg OUTPUT: -o --output +takes_value "file to write the manifest to, instead of stdout")
     )
     (@subcommand forall =>
       (about: "run a command in each project in the tree")
@@ -246,12 +480,30 @@ fn main() {
         and with the following environment variables defined:
 
           $PORE_ROOT       absolute path of the root of the tree
-          $PORE_ROOT_REL   relative path from the project to the root of the tree"
+          $PORE_ROOT_REL   relative path from the project to the root of the tree
+          $REPO_PROJECT    name of the project
+          $REPO_PATH       path of the project relative to the root of the tree
+          $REPO_REMOTE     name of the project's remote
+          $REPO_LREV       manifest revision, as the remote tracking branch it's fetched into
+          $REPO_RREV       manifest revision, as written in the manifest
+          $REPO_UPSTREAM   upstream branch of the project, if the manifest specifies one
+
+        The REPO_ variables are also exported with a PORE_ prefix."
       ))
       (@arg PATH: ...
          "path(s) beneath which to run commands\n\
          defaults to all repositories in the tree if unspecified"
       )
+      (@arg GROUP_FILTERS: -g +takes_value
+        "only run in projects that satisfy a comma delimited list of groups\n\
+         groups can be prepended with - to specifically exclude them"
+      )
+      (@arg REGEX: -r --regex +takes_value +multiple number_of_values(1)
+        "only run in projects whose name or path matches a regular expression"
+      )
+      (@arg INVERSE_REGEX: -i --("inverse-regex") requires[REGEX]
+        "only run in projects whose name and path don't match any of the regular expressions"
+      )
       (@arg COMMAND: -c +takes_value +required
         "command to run."
       )
@@ -265,7 +517,15 @@ fn main() {
     SubCommand::with_name("parse-manifest")
       .about("parse a manifest file and print it")
       .setting(clap::AppSettings::Hidden)
-      .arg(Arg::with_name("PATH").takes_value(true).required(true)),
+      .arg(Arg::with_name("PATH").takes_value(true).required(true))
+      .arg(Arg::with_name("XML").long("xml").help("print the manifest as XML")),
+  )
+  .subcommand(
+    SubCommand::with_name("promisor-upload-pack")
+      .about("serve missing objects to a checkout from its objects mirror, as git upload-pack")
+      .setting(clap::AppSettings::Hidden)
+      .arg(Arg::with_name("REMOTE").takes_value(true).required(true))
+      .arg(Arg::with_name("DIRECTORY").takes_value(true).required(true)),
   );
 
   let matches = app.get_matches();
@@ -316,15 +576,22 @@ fn main() {
   }
   .unwrap_or_else(|err| fatal!("failed to create job pool: {}", err));
 
+  lock::set_wait(matches.is_present("WAIT"));
+
   let result = || -> Result<i32, Error> {
     match matches.subcommand() {
       ("init", Some(submatches)) => {
         let fetch = !submatches.is_present("LOCAL");
+        let manifest_source = match submatches.value_of("MANIFEST_PATH") {
+          Some(path) => ManifestSource::External(Path::new(path)),
+          None => ManifestSource::Repo(submatches.value_of("MANIFEST_FILE").unwrap_or("default.xml")),
+        };
         cmd_clone(
           config,
           &mut pool,
           &submatches.value_of("TARGET").unwrap(),
           Some("."),
+          manifest_source,
           submatches.value_of("GROUP_FILTERS"),
           fetch,
         )
@@ -332,11 +599,16 @@ fn main() {
 
       ("clone", Some(submatches)) => {
         let fetch = !submatches.is_present("LOCAL");
+        let manifest_source = match submatches.value_of("MANIFEST_PATH") {
+          Some(path) => ManifestSource::External(Path::new(path)),
+          None => ManifestSource::Repo(submatches.value_of("MANIFEST_FILE").unwrap_or("default.xml")),
+        };
         cmd_clone(
           config,
           &mut pool,
           &submatches.value_of("TARGET").unwrap(),
           submatches.value_of("DIRECTORY"),
+          manifest_source,
           submatches.value_of("GROUP_FILTERS"),
           fetch,
         )
@@ -345,14 +617,17 @@ fn main() {
       ("fetch", Some(submatches)) => {
         let cwd = std::env::current_dir().context("failed to get current working directory")?;
         let mut tree = Tree::find_from_path(cwd.clone())?;
+        tree.lock()?;
         let sync_under = submatches.values_of("PATH").map(|values| values.collect());
         cmd_sync(
           config,
           &mut pool,
           &mut tree,
           sync_under,
+          None,
           FetchType::Fetch,
           CheckoutType::NoCheckout,
+          false,
         )
       }
 
@@ -364,22 +639,58 @@ fn main() {
         };
         let cwd = std::env::current_dir().context("failed to get current working directory")?;
         let mut tree = Tree::find_from_path(cwd.clone())?;
+        tree.lock()?;
         let sync_under = submatches.values_of("PATH").map(|values| values.collect());
-        cmd_sync(config, &mut pool, &mut tree, sync_under, fetch, CheckoutType::Checkout)
+        let manifest_file = submatches.value_of("MANIFEST_FILE");
+        cmd_sync(
+          config,
+          &mut pool,
+          &mut tree,
+          sync_under,
+          manifest_file,
+          fetch,
+          CheckoutType::Checkout,
+          submatches.is_present("PRUNE_REMOVED"),
+        )
       }
 
       ("start", Some(submatches)) => {
         let cwd = std::env::current_dir().context("failed to get current working directory")?;
         let mut tree = Tree::find_from_path(cwd.clone())?;
+        tree.lock()?;
         let branch_name = submatches.value_of("BRANCH").unwrap();
         cmd_start(config, &mut tree, branch_name, &cwd)
       }
 
-      ("upload", Some(submatches)) => unimplemented_subcommand("upload"),
+      ("upload", Some(submatches)) => {
+        let cwd = std::env::current_dir().context("failed to get current working directory")?;
+        let mut tree = Tree::find_from_path(cwd.clone())?;
+        tree.lock()?;
+        let upload_under = submatches.values_of("PATH").map(|values| values.collect());
+        let split = |name| -> Vec<String> {
+          submatches
+            .value_of(name)
+            .map(|value| value.split(',').map(String::from).collect())
+            .unwrap_or_default()
+        };
+        let options = UploadOptions {
+          topic: submatches.value_of("TOPIC").map(String::from),
+          reviewers: split("REVIEWERS"),
+          cc: split("CC"),
+          hashtags: split("HASHTAGS"),
+          private: submatches.is_present("PRIVATE"),
+          wip: submatches.is_present("WIP"),
+          dry_run: submatches.is_present("DRY_RUN"),
+          yes: submatches.is_present("YES"),
+          no_verify: submatches.is_present("NO_VERIFY"),
+        };
+        cmd_upload(config, &mut pool, &mut tree, upload_under, &options)
+      }
 
       ("prune", Some(submatches)) => {
         let cwd = std::env::current_dir().context("failed to get current working directory")?;
         let mut tree = Tree::find_from_path(cwd.clone())?;
+        tree.lock()?;
         cmd_prune(config, &mut pool, &mut tree)
       }
 
@@ -387,7 +698,23 @@ fn main() {
         let cwd = std::env::current_dir().context("failed to get current working directory")?;
         let tree = Tree::find_from_path(cwd.clone())?;
         let status_under = submatches.values_of("PATH").map(|values| values.collect());
-        tree.status(config, &mut pool, status_under)
+        let format = submatches.value_of("FORMAT").unwrap();
+        if submatches.is_present("STRAY") {
+          cmd_status_stray(config, &tree, status_under, format)
+        } else {
+          cmd_status(config, &mut pool, &tree, status_under, format)
+        }
+      }
+
+      ("manifest", Some(submatches)) => {
+        let cwd = std::env::current_dir().context("failed to get current working directory")?;
+        let tree = Tree::find_from_path(cwd.clone())?;
+        cmd_manifest(
+          config,
+          &tree,
+          submatches.is_present("PINNED"),
+          submatches.value_of("OUTPUT"),
+        )
       }
 
       ("forall", Some(submatches)) => {
@@ -397,7 +724,18 @@ fn main() {
         let command = submatches
           .value_of("COMMAND")
           .ok_or_else(|| format_err!("no commands specified"))?;
-        cmd_forall(config, &mut pool, &mut tree, forall_under, command)
+
+        let mut regexes = Vec::new();
+        for regex in submatches.values_of("REGEX").into_iter().flatten() {
+          regexes.push(regex::Regex::new(regex).context(format!("invalid regular expression '{}'", regex))?);
+        }
+
+        let options = ForallOptions {
+          group_filters: parse_group_filters(submatches.value_of("GROUP_FILTERS")),
+          regexes,
+          inverse_regex: submatches.is_present("INVERSE_REGEX"),
+        };
+        cmd_forall(config, &mut pool, &mut tree, forall_under, &options, command)
       }
 
       ("config", Some(submatches)) => {
@@ -406,10 +744,24 @@ fn main() {
       }
 
       ("parse-manifest", Some(submatches)) => {
-        println!("{:?}", Manifest::parse_file(submatches.value_of("PATH").unwrap())?);
+        let manifest = Manifest::parse_file(submatches.value_of("PATH").unwrap())?;
+        if submatches.is_present("XML") {
+          manifest.serialize(std::io::stdout())?;
+        } else {
+          println!("{:?}", manifest);
+        }
         Ok(0)
       }
 
+      ("promisor-upload-pack", Some(submatches)) => {
+        // This is run by git in the background, so there's nobody to tell that the objects mirror is busy.
+        lock::set_wait(true);
+        Depot::promisor_upload_pack(
+          submatches.value_of("REMOTE").unwrap(),
+          Path::new(submatches.value_of("DIRECTORY").unwrap()),
+        )
+      }
+
       _ => {
         unreachable!();
       }
@@ -15,13 +15,17 @@
  */
 
 use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use failure::{Error, ResultExt};
 
+use super::util;
+
 mod parser;
+mod serializer;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, PartialEq)]
 pub struct Manifest {
   pub remotes: HashMap<String, Remote>,
   pub projects: BTreeMap<PathBuf, Project>,
@@ -30,7 +34,7 @@ pub struct Manifest {
   pub repohooks: Option<RepoHooks>,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, PartialEq)]
 pub struct Remote {
   pub name: String,
   pub alias: Option<String>,
@@ -38,30 +42,73 @@ pub struct Remote {
   pub review: Option<String>,
 }
 
-#[derive(Default, Debug)]
+impl Remote {
+  /// Resolve the fetch URL of the remote, which may be relative to the URL of the manifest repository.
+  pub fn fetch_url(&self, manifest_url: &str) -> Result<String, Error> {
+    let mut fetch_url = if self.fetch.contains("://") || util::is_scp_url(&self.fetch) {
+      self.fetch.clone()
+    } else {
+      Remote::join_url(manifest_url.trim_end_matches('/'), &self.fetch)?
+    };
+    // Project names are appended to the fetch URL, so make sure there's a separator, unless it's the home directory
+    // of an scp-style URL.
+    if !fetch_url.ends_with('/') && !fetch_url.ends_with(':') {
+      fetch_url.push('/');
+    }
+    Ok(fetch_url)
+  }
+
+  fn join_url(base: &str, relative: &str) -> Result<String, Error> {
+    // scp-style URLs can't be parsed as URLs, so do what repo does, and resolve against a fake URL instead.
+    let scp = util::is_scp_url(base);
+    let base = if scp {
+      format!("gopher://{}", base.replacen(':', "/", 1))
+    } else {
+      base.to_string()
+    };
+
+    let base_url = url::Url::parse(&base).context(format!("failed to parse manifest URL {}", base))?;
+    let joined = base_url
+      .join(relative)
+      .context(format!("failed to resolve fetch URL {} against {}", relative, base))?;
+    if !scp {
+      return Ok(joined.into_string());
+    }
+
+    let user = match joined.username() {
+      "" => String::new(),
+      user => format!("{}@", user),
+    };
+    let host = joined.host_str().unwrap_or("");
+    Ok(format!("{}{}:{}", user, host, &joined.path()[1..]))
+  }
+}
+
+#[derive(Default, Debug, PartialEq)]
 pub struct Default {
   pub revision: Option<String>,
   pub remote: Option<String>,
   pub sync_j: Option<u32>,
-  pub sync_c: Option<u32>,
+  pub sync_c: Option<bool>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ManifestServer {
   pub url: String,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, PartialEq)]
 pub struct Project {
   pub name: String,
   pub path: Option<String>,
   pub remote: Option<String>,
   pub revision: Option<String>,
+  pub upstream: Option<String>,
 
   pub dest_branch: Option<String>,
   pub groups: Option<Vec<String>>,
 
-  pub sync_c: Option<u32>,
+  pub sync_c: Option<bool>,
   pub clone_depth: Option<u32>,
 
   pub file_operations: Vec<FileOperation>,
@@ -73,7 +120,7 @@ impl Project {
   }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum FileOperation {
   LinkFile { src: String, dst: String },
   CopyFile { src: String, dst: String },
@@ -95,21 +142,190 @@ impl FileOperation {
   }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, PartialEq)]
 pub struct RepoHooks {
   pub in_project: Option<String>,
   pub enabled_list: Option<String>,
 }
 
 impl Manifest {
-  pub fn parse(data: &[u8]) -> Result<Manifest, Error> {
-    let data_str = std::str::from_utf8(data).context(format_err!("invalid UTF-8 in manifest"))?;
-    parser::parse(data_str)
-  }
-
+  /// Parse a manifest file, resolving <include> elements relative to the directory that contains it.
   pub fn parse_file(path: impl AsRef<Path>) -> Result<Manifest, Error> {
     let path = path.as_ref();
-    let data = std::fs::read(path).context(format!("failed to read {:?}", path))?;
-    Manifest::parse(&data)
+    let canonical_path = std::fs::canonicalize(path).context(format!("failed to read {:?}", path))?;
+    let manifest_dir = canonical_path
+      .parent()
+      .ok_or_else(|| format_err!("manifest {:?} has no parent directory", canonical_path))?;
+    Manifest::parse_file_in_dir(path, manifest_dir)
+  }
+
+  /// Parse a manifest file, resolving <include> elements relative to `manifest_dir`.
+  pub fn parse_file_in_dir(path: impl AsRef<Path>, manifest_dir: impl AsRef<Path>) -> Result<Manifest, Error> {
+    let mut manifest = Manifest::default();
+    parser::parse_file(&mut manifest, manifest_dir.as_ref(), path.as_ref())?;
+    Ok(manifest)
+  }
+
+  /// Parse a manifest file on top of this manifest, like repo does with local manifests.
+  pub fn merge_file(&mut self, path: impl AsRef<Path>, manifest_dir: impl AsRef<Path>) -> Result<(), Error> {
+    parser::parse_file(self, manifest_dir.as_ref(), path.as_ref())
+  }
+
+  /// Write the manifest out as XML.
+  pub fn serialize(&self, output: impl Write) -> Result<(), Error> {
+    serializer::serialize(self, output)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+      .join("testdata")
+      .join("manifests")
+      .join(name)
+  }
+
+  /// Parse a manifest, serialize it, and check that parsing the output gives back the same manifest.
+  fn round_trip(name: &str) -> Manifest {
+    let manifest = Manifest::parse_file(fixture(name)).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("manifest.xml");
+    manifest.serialize(std::fs::File::create(&path).unwrap()).unwrap();
+    let reparsed = Manifest::parse_file(&path).unwrap();
+    assert_eq!(manifest, reparsed);
+
+    // Serialization should be stable, too.
+    let mut first = Vec::new();
+    let mut second = Vec::new();
+    manifest.serialize(&mut first).unwrap();
+    reparsed.serialize(&mut second).unwrap();
+    assert_eq!(String::from_utf8(first).unwrap(), String::from_utf8(second).unwrap());
+
+    manifest
+  }
+
+  #[test]
+  fn round_trip_aosp() {
+    let manifest = round_trip("aosp.xml");
+    assert_eq!(manifest.projects.len(), 10);
+    let build = &manifest.projects[Path::new("build/make")];
+    assert_eq!(build.file_operations.len(), 7);
+    assert!(manifest.repohooks.is_some());
+  }
+
+  #[test]
+  fn round_trip_pinned() {
+    let manifest = round_trip("pinned.xml");
+    assert_eq!(manifest.default.as_ref().unwrap().sync_c, Some(true));
+    assert_eq!(manifest.projects[Path::new("vendor/partner")].sync_c, Some(false));
+    assert_eq!(
+      manifest.projects[Path::new("external/kernel-headers")].sync_c,
+      Some(true)
+    );
+  }
+
+  #[test]
+  fn round_trip_include() {
+    let manifest = round_trip("with_include.xml");
+    assert!(!manifest.projects.contains_key(Path::new("art")));
+    let bionic = &manifest.projects[Path::new("bionic")];
+    assert_eq!(bionic.revision.as_deref(), Some("bionic-dev"));
+    assert_eq!(manifest.projects[Path::new("vendor/extra")].sync_c, Some(true));
+  }
+
+  /// Parse a manifest that includes another one, both of which declare the same remote and default.
+  fn parse_redeclared(included_default: &str) -> Result<Manifest, Error> {
+    let dir = tempfile::tempdir().unwrap();
+    let elements = r#"<remote name="aosp" fetch=".." /><default revision="master" remote="aosp" />"#;
+    std::fs::write(
+      dir.path().join("default.xml"),
+      format!(r#"<manifest>{}<include name="included.xml" /></manifest>"#, elements),
+    )
+    .unwrap();
+    std::fs::write(
+      dir.path().join("included.xml"),
+      format!(
+        r#"<manifest><remote name="aosp" fetch=".." />{}</manifest>"#,
+        included_default
+      ),
+    )
+    .unwrap();
+    Manifest::parse_file(dir.path().join("default.xml"))
+  }
+
+  #[test]
+  fn include_redeclares_identical_elements() {
+    let manifest = parse_redeclared(r#"<default revision="master" remote="aosp" />"#).unwrap();
+    assert_eq!(manifest.remotes.len(), 1);
+    assert_eq!(manifest.default.unwrap().revision.as_deref(), Some("master"));
+  }
+
+  #[test]
+  fn include_redeclares_conflicting_elements() {
+    let error = parse_redeclared(r#"<default revision="stable" remote="aosp" />"#).unwrap_err();
+    let message = error
+      .iter_chain()
+      .map(|cause| cause.to_string())
+      .collect::<Vec<_>>()
+      .join(": ");
+    assert!(
+      message.contains("manifest.default already has a different value"),
+      "{}",
+      message
+    );
+    assert!(message.contains("included.xml"), "{}", message);
+  }
+
+  fn fetch_url(fetch: &str, manifest_url: &str) -> String {
+    let remote = Remote {
+      name: "origin".into(),
+      fetch: fetch.into(),
+      ..Remote::default()
+    };
+    remote.fetch_url(manifest_url).unwrap()
+  }
+
+  #[test]
+  fn fetch_url_relative() {
+    assert_eq!(
+      fetch_url("..", "https://android.googlesource.com/platform/manifest"),
+      "https://android.googlesource.com/"
+    );
+    assert_eq!(
+      fetch_url("../..", "https://example.com/a/platform/manifest/"),
+      "https://example.com/"
+    );
+  }
+
+  #[test]
+  fn fetch_url_absolute() {
+    assert_eq!(
+      fetch_url(
+        "https://example.com/git",
+        "https://android.googlesource.com/platform/manifest"
+      ),
+      "https://example.com/git/"
+    );
+    assert_eq!(
+      fetch_url(
+        "git@example.com:git",
+        "https://android.googlesource.com/platform/manifest"
+      ),
+      "git@example.com:git/"
+    );
+  }
+
+  #[test]
+  fn fetch_url_scp_manifest() {
+    assert_eq!(fetch_url("..", "git@example.com:platform/manifest"), "git@example.com:");
+    assert_eq!(
+      fetch_url("../mirror", "git@example.com:aosp/platform/manifest"),
+      "git@example.com:aosp/mirror/"
+    );
+    assert_eq!(fetch_url(".", "example.com:/srv/manifest"), "example.com:/srv/");
   }
 }
@@ -1,3 +1,5 @@
+use std::path::{Path, PathBuf};
+
 use failure::{Error, ResultExt};
 use quick_xml::events::{BytesStart, Event};
 use quick_xml::Reader;
@@ -5,6 +7,7 @@ use quick_xml::Reader;
 use crate::manifest::*;
 
 /// Assign a value to an Option after asserting that it is None.
+/// With a context, an identical value is also accepted, since included manifests may repeat a declaration.
 macro_rules! populate_option {
   ($option: expr, $value: expr) => {{
     if $option.is_some() {
@@ -12,10 +15,70 @@ macro_rules! populate_option {
     }
     $option = Some($value);
   }};
+
+  ($option: expr, $value: expr, $context: expr) => {{
+    let value = $value;
+    match &$option {
+      Some(existing) if *existing != value => bail!(
+        "{} already has a different value in {}",
+        stringify!($option),
+        $context.current_file()
+      ),
+      Some(_) => {}
+      None => $option = Some(value),
+    }
+  }};
+}
+
+/// State that persists across included manifest files.
+struct Context<'a> {
+  /// Directory that <include> names are resolved against, if any.
+  manifest_dir: Option<&'a Path>,
+
+  /// Stack of files currently being parsed, used to detect include cycles.
+  files: Vec<PathBuf>,
+}
+
+impl<'a> Context<'a> {
+  fn current_file(&self) -> String {
+    match self.files.last() {
+      Some(path) => format!("{:?}", path),
+      None => "manifest".into(),
+    }
+  }
 }
 
-pub fn parse(data: &str) -> Result<Manifest, Error> {
-  let mut manifest = None;
+/// Parse the manifest at `path` into `manifest`, resolving <include> elements relative to `manifest_dir`.
+pub fn parse_file(manifest: &mut Manifest, manifest_dir: &Path, path: &Path) -> Result<(), Error> {
+  let mut context = Context {
+    manifest_dir: Some(manifest_dir),
+    files: Vec::new(),
+  };
+  parse_file_into(manifest, path, &mut context)
+}
+
+fn parse_file_into(manifest: &mut Manifest, path: &Path, context: &mut Context) -> Result<(), Error> {
+  let canonical_path = std::fs::canonicalize(path).context(format!("failed to find manifest {:?}", path))?;
+  if let Some(index) = context.files.iter().position(|file| file == &canonical_path) {
+    let cycle: Vec<String> = context.files[index..]
+      .iter()
+      .chain(std::iter::once(&canonical_path))
+      .map(|file| format!("{:?}", file))
+      .collect();
+    bail!("include cycle detected: {}", cycle.join(" -> "));
+  }
+
+  let data = std::fs::read(&canonical_path).context(format!("failed to read {:?}", canonical_path))?;
+  let data_str = std::str::from_utf8(&data).context(format_err!("invalid UTF-8 in manifest {:?}", canonical_path))?;
+
+  context.files.push(canonical_path);
+  let result = parse_data(manifest, data_str, context);
+  context.files.pop();
+  result
+}
+
+fn parse_data(manifest: &mut Manifest, data: &str, context: &mut Context) -> Result<(), Error> {
+  let mut found_manifest = false;
 
   let mut reader = Reader::from_str(data);
   reader.trim_text(true);
@@ -31,12 +94,13 @@ pub fn parse(data: &str) -> Result<Manifest, Error> {
       Event::Start(e) => {
         let tag_name = e.name();
         match tag_name {
-          b"manifest" => match manifest {
-            Some(_) => bail!("multiple manifest tags in manifest"),
-            None => {
-              manifest = Some(parse_manifest(&e, &mut reader)?);
+          b"manifest" => {
+            if found_manifest {
+              bail!("multiple manifest tags in {}", context.current_file());
             }
-          },
+            found_manifest = true;
+            parse_manifest(manifest, &e, &mut reader, context)?;
+          }
 
           _ => bail!(
             "unexpected start tag in manifest.xml: {}",
@@ -62,11 +126,20 @@ pub fn parse(data: &str) -> Result<Manifest, Error> {
     }
   }
 
-  Ok(manifest.ok_or_else(|| format_err!("failed to find a manifest tag"))?)
+  ensure!(
+    found_manifest,
+    "failed to find a manifest tag in {}",
+    context.current_file()
+  );
+  Ok(())
 }
 
-fn parse_manifest(event: &BytesStart, mut reader: &mut Reader<&[u8]>) -> Result<Manifest, Error> {
-  let mut manifest = Manifest::default();
+fn parse_manifest(
+  manifest: &mut Manifest,
+  event: &BytesStart,
+  mut reader: &mut Reader<&[u8]>,
+  context: &mut Context,
+) -> Result<(), Error> {
   let mut buf = Vec::new();
   loop {
     let event = reader.read_event(&mut buf).context(format_err!(
@@ -80,11 +153,7 @@ fn parse_manifest(event: &BytesStart, mut reader: &mut Reader<&[u8]>) -> Result<
         match tag_name {
           b"project" => {
             let project = parse_project(&e, &mut reader, true)?;
-            let path = PathBuf::from(project.path());
-            if manifest.projects.contains_key(&path) {
-              bail!("duplicate project {:?}", path);
-            }
-            manifest.projects.insert(path, project);
+            add_project(manifest, project, context)?;
           }
 
           _ => bail!(
@@ -97,24 +166,45 @@ fn parse_manifest(event: &BytesStart, mut reader: &mut Reader<&[u8]>) -> Result<
       Event::Empty(e) => match e.name() {
         b"project" => {
           let project = parse_project(&e, &mut reader, false)?;
-          let path = PathBuf::from(project.path());
-          if manifest.projects.contains_key(&path) {
-            bail!("duplicate project {:?}", path);
-          }
-          manifest.projects.insert(path, project);
+          add_project(manifest, project, context)?;
         }
 
         b"remote" => {
           let remote = parse_remote(&e, &reader)?;
-          if manifest.remotes.contains_key(&remote.name) {
-            bail!("duplicate remotes with name {}", remote.name);
+          if let Some(existing) = manifest.remotes.get(&remote.name) {
+            if *existing != remote {
+              bail!(
+                "conflicting remotes with name {} in {}",
+                remote.name,
+                context.current_file()
+              );
+            }
           }
           manifest.remotes.insert(remote.name.clone(), remote);
         }
 
-        b"default" => populate_option!(manifest.default, parse_default(&e, &reader)?),
-        b"manifest-server" => populate_option!(manifest.manifest_server, parse_manifest_server(&e, &reader)?),
-        b"repo-hooks" => populate_option!(manifest.repohooks, parse_repo_hooks(&e, &reader)?),
+        b"remove-project" => {
+          let remove = parse_remove_project(&e, reader)?;
+          remove_project(manifest, remove, context)?;
+        }
+
+        b"extend-project" => {
+          let extend = parse_extend_project(&e, reader)?;
+          extend_project(manifest, extend, context)?;
+        }
+
+        b"include" => {
+          let name = parse_include(&e, reader)?;
+          let manifest_dir = context
+            .manifest_dir
+            .ok_or_else(|| format_err!("<include name=\"{}\"> requires a manifest directory", name))?;
+          let message = format!("failed to include {} from {}", name, context.current_file());
+          parse_file_into(manifest, &manifest_dir.join(&name), context).context(message)?;
+        }
+
+        b"default" => populate_option!(manifest.default, parse_default(&e, &reader)?, context),
+        b"manifest-server" => populate_option!(manifest.manifest_server, parse_manifest_server(&e, &reader)?, context),
+        b"repo-hooks" => populate_option!(manifest.repohooks, parse_repo_hooks(&e, &reader)?, context),
 
         _ => bail!(
           "unexpected empty element in <manifest>: {}",
@@ -133,7 +223,178 @@ fn parse_manifest(event: &BytesStart, mut reader: &mut Reader<&[u8]>) -> Result<
     }
   }
 
-  Ok(manifest)
+  Ok(())
+}
+
+fn add_project(manifest: &mut Manifest, project: Project, context: &Context) -> Result<(), Error> {
+  let path = PathBuf::from(project.path());
+  if manifest.projects.contains_key(&path) {
+    bail!("duplicate project {:?} in {}", path, context.current_file());
+  }
+  manifest.projects.insert(path, project);
+  Ok(())
+}
+
+/// A <remove-project> element, which removes projects that were previously declared.
+struct RemoveProject {
+  name: String,
+  path: Option<String>,
+}
+
+/// An <extend-project> element, which modifies projects that were previously declared.
+#[derive(Default)]
+struct ExtendProject {
+  name: String,
+  path: Option<String>,
+
+  dest_path: Option<String>,
+  groups: Option<Vec<String>>,
+  revision: Option<String>,
+  upstream: Option<String>,
+  remote: Option<String>,
+  dest_branch: Option<String>,
+}
+
+/// Find the paths of the projects named `name`, optionally restricted to a single path.
+fn find_projects(manifest: &Manifest, name: &str, path: Option<&str>) -> Vec<PathBuf> {
+  manifest
+    .projects
+    .iter()
+    .filter(|(project_path, project)| project.name == name && path.is_none_or(|path| Path::new(path) == *project_path))
+    .map(|(project_path, _)| project_path.clone())
+    .collect()
+}
+
+fn remove_project(manifest: &mut Manifest, remove: RemoveProject, context: &Context) -> Result<(), Error> {
+  let paths = find_projects(manifest, &remove.name, remove.path.as_deref());
+  ensure!(
+    !paths.is_empty(),
+    "<remove-project> refers to nonexistent project {} in {}",
+    remove.name,
+    context.current_file()
+  );
+
+  for path in paths {
+    manifest.projects.remove(&path);
+  }
+  Ok(())
+}
+
+fn extend_project(manifest: &mut Manifest, extend: ExtendProject, context: &Context) -> Result<(), Error> {
+  let paths = find_projects(manifest, &extend.name, extend.path.as_deref());
+  ensure!(
+    !paths.is_empty(),
+    "<extend-project> refers to nonexistent project {} in {}",
+    extend.name,
+    context.current_file()
+  );
+  ensure!(
+    extend.dest_path.is_none() || paths.len() == 1,
+    "<extend-project> with dest-path refers to multiple projects named {} in {}, specify a path",
+    extend.name,
+    context.current_file()
+  );
+
+  for path in paths {
+    let mut project = manifest.projects.remove(&path).unwrap();
+
+    if let Some(groups) = &extend.groups {
+      project
+        .groups
+        .get_or_insert_with(Vec::new)
+        .extend(groups.iter().cloned());
+    }
+    if let Some(revision) = &extend.revision {
+      project.revision = Some(revision.clone());
+    }
+    if let Some(upstream) = &extend.upstream {
+      project.upstream = Some(upstream.clone());
+    }
+    if let Some(remote) = &extend.remote {
+      project.remote = Some(remote.clone());
+    }
+    if let Some(dest_branch) = &extend.dest_branch {
+      project.dest_branch = Some(dest_branch.clone());
+    }
+
+    if let Some(dest_path) = &extend.dest_path {
+      project.path = Some(dest_path.clone());
+      add_project(manifest, project, context)?;
+    } else {
+      manifest.projects.insert(path, project);
+    }
+  }
+  Ok(())
+}
+
+fn parse_remove_project(event: &BytesStart, reader: &Reader<&[u8]>) -> Result<RemoveProject, Error> {
+  let mut name = None;
+  let mut path = None;
+  for attribute in event.attributes() {
+    let attribute = attribute?;
+    let value = attribute.unescape_and_decode_value(reader)?;
+    match attribute.key {
+      b"name" => populate_option!(name, value),
+      b"path" => populate_option!(path, value),
+      key => bail!(
+        "unexpected attribute in <remove-project>: {}",
+        std::str::from_utf8(key).unwrap_or("???")
+      ),
+    }
+  }
+
+  let name = name.ok_or_else(|| format_err!("name not specified in <remove-project>"))?;
+  Ok(RemoveProject { name, path })
+}
+
+fn parse_extend_project(event: &BytesStart, reader: &Reader<&[u8]>) -> Result<ExtendProject, Error> {
+  let mut extend = ExtendProject::default();
+  let mut name = None;
+  for attribute in event.attributes() {
+    let attribute = attribute?;
+    let value = attribute.unescape_and_decode_value(reader)?;
+    match attribute.key {
+      b"name" => populate_option!(name, value),
+      b"path" => populate_option!(extend.path, value),
+      b"dest-path" => populate_option!(extend.dest_path, value),
+      b"groups" => populate_option!(extend.groups, value.split(',').map(|s| s.to_string()).collect()),
+      b"revision" => populate_option!(extend.revision, value),
+      b"upstream" => populate_option!(extend.upstream, value),
+      b"remote" => populate_option!(extend.remote, value),
+      b"dest-branch" => populate_option!(extend.dest_branch, value),
+      key => bail!(
+        "unexpected attribute in <extend-project>: {}",
+        std::str::from_utf8(key).unwrap_or("???")
+      ),
+    }
+  }
+
+  extend.name = name.ok_or_else(|| format_err!("name not specified in <extend-project>"))?;
+  Ok(extend)
+}
+
+fn parse_include(event: &BytesStart, reader: &Reader<&[u8]>) -> Result<String, Error> {
+  let mut name = None;
+  for attribute in event.attributes() {
+    let attribute = attribute?;
+    let value = attribute.unescape_and_decode_value(reader)?;
+    match attribute.key {
+      b"name" => populate_option!(name, value),
+      key => bail!(
+        "unexpected attribute in <include>: {}",
+        std::str::from_utf8(key).unwrap_or("???")
+      ),
+    }
+  }
+
+  let name = name.ok_or_else(|| format_err!("name not specified in <include>"))?;
+
+  // repo refuses to include files outside of the manifest repository.
+  let valid = Path::new(&name)
+    .components()
+    .all(|component| matches!(component, std::path::Component::Normal(_)));
+  ensure!(valid, "invalid include name {}", name);
+  Ok(name)
 }
 
 fn parse_remote(event: &BytesStart, reader: &Reader<&[u8]>) -> Result<Remote, Error> {
@@ -164,6 +425,15 @@ fn parse_remote(event: &BytesStart, reader: &Reader<&[u8]>) -> Result<Remote, Er
   Ok(remote)
 }
 
+/// Parse a boolean attribute the way repo does.
+fn parse_bool(value: &str) -> Result<bool, Error> {
+  match value.to_lowercase().as_str() {
+    "true" | "yes" | "1" => Ok(true),
+    "false" | "no" | "0" => Ok(false),
+    _ => bail!("invalid boolean value '{}'", value),
+  }
+}
+
 fn parse_default(event: &BytesStart, reader: &Reader<&[u8]>) -> Result<Default, Error> {
   let mut default = Default::default();
 
@@ -174,7 +444,7 @@ fn parse_default(event: &BytesStart, reader: &Reader<&[u8]>) -> Result<Default,
       b"revision" => populate_option!(default.revision, value),
       b"remote" => populate_option!(default.remote, value),
       b"sync-j" => populate_option!(default.sync_j, value.parse::<u32>().context("failed to parse sync-j")?),
-      b"sync-c" => populate_option!(default.sync_j, value.parse::<u32>().context("failed to parse sync-c")?),
+      b"sync-c" => populate_option!(default.sync_c, parse_bool(&value).context("failed to parse sync-c")?),
       key => bail!(
         "unexpected attribute in <default>: {}",
         std::str::from_utf8(key).unwrap_or("???")
@@ -214,9 +484,10 @@ fn parse_project(event: &BytesStart, reader: &mut Reader<&[u8]>, has_children: b
       b"path" => populate_option!(project.path, value),
       b"remote" => populate_option!(project.remote, value),
       b"revision" => populate_option!(project.revision, value),
+      b"upstream" => populate_option!(project.upstream, value),
       b"dest-branch" => populate_option!(project.dest_branch, value),
       b"groups" => populate_option!(project.groups, value.split(',').map(|s| s.to_string()).collect()),
-      b"sync-c" => populate_option!(project.sync_c, value.parse::<u32>().context("failed to parse sync-c")?),
+      b"sync-c" => populate_option!(project.sync_c, parse_bool(&value).context("failed to parse sync-c")?),
       b"clone-depth" => populate_option!(
         project.clone_depth,
         value.parse::<u32>().context("failed to parse clone-depth")?
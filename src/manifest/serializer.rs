@@ -0,0 +1,112 @@
+use std::io::Write;
+
+use failure::{Error, ResultExt};
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, Event};
+use quick_xml::Writer;
+
+use crate::manifest::*;
+
+/// Add an attribute to an element if its value is present.
+macro_rules! push_option {
+  ($element: expr, $key: expr, $value: expr) => {{
+    if let Some(value) = &$value {
+      $element.push_attribute(($key, value.to_string().as_str()));
+    }
+  }};
+}
+
+pub fn serialize(manifest: &Manifest, mut output: impl Write) -> Result<(), Error> {
+  // quick-xml doesn't use write_all, so write to a buffer before writing to the real output.
+  let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+  writer.write_event(Event::Decl(BytesDecl::new(b"1.0", Some(b"UTF-8"), None)))?;
+  writer.write_event(Event::Start(BytesStart::borrowed_name(b"manifest")))?;
+
+  // Remotes are stored in a HashMap, so sort them to get stable output.
+  let mut remotes: Vec<&Remote> = manifest.remotes.values().collect();
+  remotes.sort_by(|a, b| a.name.cmp(&b.name));
+  for remote in remotes {
+    writer.write_event(Event::Empty(serialize_remote(remote)))?;
+  }
+
+  if let Some(default) = &manifest.default {
+    writer.write_event(Event::Empty(serialize_default(default)))?;
+  }
+
+  if let Some(manifest_server) = &manifest.manifest_server {
+    let mut element = BytesStart::borrowed_name(b"manifest-server");
+    element.push_attribute(("url", manifest_server.url.as_str()));
+    writer.write_event(Event::Empty(element))?;
+  }
+
+  for project in manifest.projects.values() {
+    serialize_project(&mut writer, project)?;
+  }
+
+  if let Some(repohooks) = &manifest.repohooks {
+    let mut element = BytesStart::borrowed_name(b"repo-hooks");
+    push_option!(element, "in-project", repohooks.in_project);
+    push_option!(element, "enabled-list", repohooks.enabled_list);
+    writer.write_event(Event::Empty(element))?;
+  }
+
+  writer.write_event(Event::End(BytesEnd::borrowed(b"manifest")))?;
+
+  let mut buf = writer.into_inner();
+  buf.push(b'\n');
+  output.write_all(&buf).context("failed to write manifest")?;
+  Ok(())
+}
+
+fn serialize_remote(remote: &Remote) -> BytesStart<'_> {
+  let mut element = BytesStart::borrowed_name(b"remote");
+  element.push_attribute(("name", remote.name.as_str()));
+  push_option!(element, "alias", remote.alias);
+  element.push_attribute(("fetch", remote.fetch.as_str()));
+  push_option!(element, "review", remote.review);
+  element
+}
+
+fn serialize_default(default: &Default) -> BytesStart<'_> {
+  let mut element = BytesStart::borrowed_name(b"default");
+  push_option!(element, "revision", default.revision);
+  push_option!(element, "remote", default.remote);
+  push_option!(element, "sync-j", default.sync_j);
+  push_option!(element, "sync-c", default.sync_c);
+  element
+}
+
+fn serialize_project(writer: &mut Writer<Vec<u8>>, project: &Project) -> Result<(), Error> {
+  let mut element = BytesStart::borrowed_name(b"project");
+  element.push_attribute(("name", project.name.as_str()));
+  push_option!(element, "path", project.path);
+  push_option!(element, "remote", project.remote);
+  push_option!(element, "revision", project.revision);
+  push_option!(element, "upstream", project.upstream);
+  push_option!(element, "dest-branch", project.dest_branch);
+  push_option!(
+    element,
+    "groups",
+    project.groups.as_ref().map(|groups| groups.join(","))
+  );
+  push_option!(element, "sync-c", project.sync_c);
+  push_option!(element, "clone-depth", project.clone_depth);
+
+  if project.file_operations.is_empty() {
+    writer.write_event(Event::Empty(element))?;
+    return Ok(());
+  }
+
+  writer.write_event(Event::Start(element))?;
+  for op in &project.file_operations {
+    let name: &[u8] = match op {
+      FileOperation::CopyFile { .. } => b"copyfile",
+      FileOperation::LinkFile { .. } => b"linkfile",
+    };
+    let mut child = BytesStart::borrowed_name(name);
+    child.push_attribute(("src", op.src()));
+    child.push_attribute(("dest", op.dst()));
+    writer.write_event(Event::Empty(child))?;
+  }
+  writer.write_event(Event::End(BytesEnd::borrowed(b"project")))?;
+  Ok(())
+}
@@ -0,0 +1,165 @@
+/*
+ * Copyright (C) 2019 Josh Gao
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Helpers for tests that need real git repositories.
+
+use std::path::{Path, PathBuf};
+
+use futures::executor::ThreadPool;
+
+use super::config::Config;
+use super::depot::Depot;
+use super::tree::{CheckoutType, FetchType, ManifestSource, Tree};
+
+/// Run git in `dir` with a fixed identity, and return its stdout.
+pub fn git<T: AsRef<Path>>(dir: T, args: &[&str]) -> String {
+  let output = std::process::Command::new("git")
+    .arg("-C")
+    .arg(dir.as_ref())
+    .args(args)
+    .env("GIT_AUTHOR_NAME", "pore")
+    .env("GIT_AUTHOR_EMAIL", "pore@example.com")
+    .env("GIT_COMMITTER_NAME", "pore")
+    .env("GIT_COMMITTER_EMAIL", "pore@example.com")
+    .output()
+    .expect("failed to spawn git");
+  assert!(
+    output.status.success(),
+    "git {:?} failed: {}",
+    args,
+    String::from_utf8_lossy(&output.stderr)
+  );
+  String::from_utf8(output.stdout).unwrap()
+}
+
+/// Commit a file in a working tree.
+pub fn commit_file<T: AsRef<Path>>(dir: T, file: &str, contents: &str) {
+  let dir = dir.as_ref();
+  std::fs::write(dir.join(file), contents).unwrap();
+  git(dir, &["add", file]);
+  git(dir, &["commit", "-q", "-m", &format!("update {}", file)]);
+}
+
+/// A directory with bare repositories to act as a remote, a depot, and room for trees.
+pub struct TestServer {
+  pub dir: tempfile::TempDir,
+}
+
+impl TestServer {
+  /// Create a server with a manifest repository (platform/manifest) that checks out each project at its own name.
+  pub fn new(projects: &[&str]) -> TestServer {
+    TestServer::with_manifest_extra(projects, "")
+  }
+
+  /// Create a server like `new`, with extra XML at the end of the manifest.
+  pub fn with_manifest_extra(projects: &[&str], extra: &str) -> TestServer {
+    let server = TestServer {
+      dir: tempfile::tempdir().unwrap(),
+    };
+
+    for project in projects {
+      server.create_repo(project, &[("README", project)]);
+    }
+
+    let mut manifest = String::from(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<manifest>\n  <remote name=\"test\" fetch=\"..\" />\n  <default \
+       revision=\"master\" remote=\"test\" />\n",
+    );
+    for project in projects {
+      manifest += &format!("  <project path=\"{}\" name=\"{}\" />\n", project, project);
+    }
+    manifest += extra;
+    manifest += "</manifest>\n";
+    server.create_repo("platform/manifest", &[("default.xml", &manifest)]);
+
+    let config = format!(
+      "[[remotes]]\nname = \"test\"\nurl = \"file://{}/\"\nmanifest = \"platform/manifest\"\ndepot = \"test\"\n\n\
+       [depots.test]\npath = \"{}\"\n",
+      server.srv().display(),
+      server.dir.path().join("depot").display()
+    );
+    std::fs::write(server.dir.path().join("pore.toml"), config).unwrap();
+    server
+  }
+
+  pub fn srv(&self) -> PathBuf {
+    self.dir.path().join("srv")
+  }
+
+  pub fn repo_path(&self, project: &str) -> PathBuf {
+    self.srv().join(format!("{}.git", project))
+  }
+
+  /// Create a bare repository on the server, with a commit on master containing `files`.
+  pub fn create_repo(&self, project: &str, files: &[(&str, &str)]) {
+    let path = self.repo_path(project);
+    std::fs::create_dir_all(&path).unwrap();
+    git(&path, &["init", "-q", "--bare"]);
+
+    let work = self.dir.path().join("work").join(project);
+    std::fs::create_dir_all(&work).unwrap();
+    git(&work, &["init", "-q"]);
+    git(&work, &["checkout", "-q", "-b", "master"]);
+    for (file, contents) in files {
+      commit_file(&work, file, contents);
+    }
+    git(&work, &["push", "-q", path.to_str().unwrap(), "master"]);
+  }
+
+  pub fn config(&self) -> Config {
+    Config::from_path(&self.dir.path().join("pore.toml")).unwrap()
+  }
+
+  pub fn depot(&self) -> Depot {
+    let config = self.config();
+    let remote = config.find_remote("test").unwrap();
+    config.find_depot(&remote.depot).unwrap()
+  }
+
+  /// Clone a tree of the server's manifest, like `pore clone test`.
+  pub fn clone_tree(&self, name: &str) -> Tree {
+    let config = self.config();
+    let remote = config.find_remote("test").unwrap();
+    let depot = self.depot();
+    let path = self.dir.path().join(name);
+    std::fs::create_dir_all(&path).unwrap();
+
+    let mut tree = Tree::construct(
+      &depot,
+      &path,
+      &remote,
+      "master",
+      ManifestSource::Repo("default.xml"),
+      Vec::new(),
+      true,
+    )
+    .unwrap();
+    let mut pool = ThreadPool::new().unwrap();
+    tree
+      .sync(
+        &config,
+        &mut pool,
+        &depot,
+        None,
+        None,
+        FetchType::FetchExceptManifest,
+        CheckoutType::Checkout,
+        false,
+      )
+      .unwrap();
+    tree
+  }
+}
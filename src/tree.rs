@@ -14,25 +14,30 @@
  * limitations under the License.
  */
 
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::ops::Deref;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use failure::Error;
 use futures::executor::ThreadPool;
-use futures::future;
+use futures::future::{self, BoxFuture, FutureExt};
 use futures::task::SpawnExt;
 
 use super::*;
 use config::RemoteConfig;
-use depot::Depot;
+use depot::{Depot, TransferProgress};
+use lock::FileLock;
 use manifest::FileOperation;
 
 pub struct Tree {
   pub path: PathBuf,
   pub config: TreeConfig,
+  lock: Option<FileLock>,
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -144,21 +149,119 @@ pub struct TreeConfig {
   pub remote: String,
   pub branch: String,
   pub manifest: String,
+  pub manifest_file: Option<String>,
   pub tags: Vec<String>,
 
   pub projects: Vec<String>,
   pub group_filters: Option<Vec<GroupFilter>>,
+
+  /// Hashes of the repohooks that have been approved to run, by hook name.
+  #[serde(default)]
+  pub hook_approvals: BTreeMap<String, String>,
 }
 
 #[derive(Clone, Debug)]
 struct ProjectInfo {
   project_path: String,
   project_name: String,
+  remote: RemoteConfig,
   revision: String,
+  upstream: Option<String>,
+  dest_branch: Option<String>,
+  clone_depth: Option<u32>,
   file_ops: Vec<manifest::FileOperation>,
 }
 
-#[derive(Debug, PartialEq)]
+impl ProjectInfo {
+  /// The ref to fetch to get the project's revision.
+  fn fetch_revision(&self) -> &str {
+    // Commits can't (generally) be fetched directly, so fetch the branch they came from instead.
+    match &self.upstream {
+      Some(upstream) if util::is_commit_hash(&self.revision) => upstream,
+      _ => &self.revision,
+    }
+  }
+
+  /// The branch that changes to the project are uploaded to.
+  fn upload_branch(&self) -> &str {
+    let branch = self.dest_branch.as_deref().unwrap_or_else(|| self.fetch_revision());
+    branch.trim_start_matches("refs/heads/")
+  }
+}
+
+/// The manifest that a new tree is created with.
+#[derive(Clone, Copy, Debug)]
+pub enum ManifestSource<'a> {
+  /// A manifest file in the manifest repository, which is updated when the tree is synced.
+  Repo(&'a str),
+
+  /// A manifest file from outside of the manifest repository (e.g. the output of `pore manifest --pinned`), which is
+  /// copied into the tree and used for every sync.
+  External(&'a Path),
+}
+
+/// Filters for `pore forall`, applied on top of the tree's group filters.
+#[derive(Debug, Default)]
+pub struct ForallOptions {
+  pub group_filters: Vec<GroupFilter>,
+
+  /// Only run in projects whose name or path matches one of these.
+  pub regexes: Vec<regex::Regex>,
+
+  /// Run in the projects that don't match the regexes, instead of the ones that do.
+  pub inverse_regex: bool,
+}
+
+/// Options for `pore upload`, passed to Gerrit as part of the pushed ref.
+#[derive(Debug, Default)]
+pub struct UploadOptions {
+  pub topic: Option<String>,
+  pub reviewers: Vec<String>,
+  pub cc: Vec<String>,
+  pub hashtags: Vec<String>,
+  pub private: bool,
+  pub wip: bool,
+  pub dry_run: bool,
+  pub yes: bool,
+  pub no_verify: bool,
+}
+
+impl UploadOptions {
+  /// The refspec destination for an upload to `branch`, e.g. `refs/for/master%topic=foo,r=bar@example.com`.
+  fn destination(&self, branch: &str) -> String {
+    let mut options = Vec::new();
+    if let Some(topic) = &self.topic {
+      options.push(format!("topic={}", topic));
+    }
+    options.extend(self.reviewers.iter().map(|reviewer| format!("r={}", reviewer)));
+    options.extend(self.cc.iter().map(|cc| format!("cc={}", cc)));
+    options.extend(self.hashtags.iter().map(|hashtag| format!("t={}", hashtag)));
+    if self.private {
+      options.push("private".into());
+    }
+    if self.wip {
+      options.push("wip".into());
+    }
+
+    let mut destination = format!("refs/for/{}", branch);
+    if !options.is_empty() {
+      destination.push('%');
+      destination.push_str(&options.join(","));
+    }
+    destination
+  }
+}
+
+/// What happened to a local branch that HEAD was on during sync.
+#[derive(Debug)]
+enum BranchUpdate {
+  Rebased(String),
+  FastForwarded(String),
+  Skipped { branch: String, reason: String },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FileState {
   New,
   Modified,
@@ -169,30 +272,53 @@ pub enum FileState {
 }
 
 impl FileState {
-  fn to_char(&self) -> char {
+  pub fn to_char(&self) -> char {
     match self {
       FileState::New => 'a',
       FileState::Modified => 'm',
       FileState::Deleted => 'd',
       FileState::Renamed => 'r',
-      FileState::TypeChange => 'd',
+      FileState::TypeChange => 't',
       FileState::Unchanged => '-',
     }
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct FileStatus {
   pub filename: String,
   pub index: FileState,
   pub worktree: FileState,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct ProjectStatus {
-  project_name: String,
-  branch: Option<String>,
-  files: Vec<FileStatus>,
+  pub project_path: String,
+
+  /// The checked out branch, or None if HEAD is detached.
+  pub branch: Option<String>,
+  pub detached: bool,
+
+  /// The manifest revision that HEAD is compared against, and how many commits HEAD is ahead and behind it.
+  pub upstream: Option<String>,
+  pub ahead: usize,
+  pub behind: usize,
+
+  pub files: Vec<FileStatus>,
+}
+
+/// A file or directory in the tree that isn't part of any project.
+#[derive(Debug, Serialize)]
+pub struct StrayFile {
+  pub path: String,
+  pub directory: bool,
+}
+
+impl ProjectStatus {
+  /// Whether the project has anything that hasn't been uploaded: a branch, local changes, or unpushed commits.
+  pub fn is_dirty(&self) -> bool {
+    self.branch.is_some() || self.ahead > 0 || !self.files.is_empty()
+  }
 }
 
 impl Tree {
@@ -201,38 +327,69 @@ impl Tree {
     path: T,
     remote_config: &RemoteConfig,
     branch: &str,
+    manifest_source: ManifestSource,
     group_filters: Vec<GroupFilter>,
     fetch: bool,
   ) -> Result<Tree, Error> {
     let tree_root = path.into();
 
-    // TODO: Add locking?
     util::assert_empty_directory(&tree_root)?;
     let pore_path = tree_root.join(".pore");
     let remote = &remote_config.name;
 
     std::fs::create_dir_all(&pore_path).context(format!("failed to create directory {:?}", pore_path))?;
+    let lock = Tree::acquire_lock(&tree_root)?;
 
     let manifest_path = pore_path.join("manifest");
-    symlink("manifest/default.xml", pore_path.join("manifest.xml")).context("failed to create manifest symlink")?;
+    let manifest_file = match manifest_source {
+      ManifestSource::Repo(manifest_file) => {
+        let valid = Path::new(manifest_file)
+          .components()
+          .all(|component| matches!(component, std::path::Component::Normal(_)));
+        ensure!(valid, "invalid manifest file {}", manifest_file);
+        symlink(
+          Path::new("manifest").join(manifest_file),
+          pore_path.join("manifest.xml"),
+        )
+        .context("failed to create manifest symlink")?;
+        Some(manifest_file)
+      }
+
+      // The manifest repository is still checked out, for its includes and repohooks.
+      ManifestSource::External(path) => {
+        std::fs::copy(path, pore_path.join("manifest.xml")).context(format!("failed to copy manifest {:?}", path))?;
+        None
+      }
+    };
 
     if fetch {
-      depot.fetch_repo(&remote_config, &remote_config.manifest, &branch, None, None)?;
+      depot.fetch_repo(&remote_config, &remote_config.manifest, &[branch], None, None)?;
     }
     depot.clone_repo(&remote_config, &remote_config.manifest, &branch, &manifest_path)?;
+    if let Some(manifest_file) = manifest_file {
+      ensure!(
+        manifest_path.join(manifest_file).is_file(),
+        "manifest file {} doesn't exist in {}",
+        manifest_file,
+        remote_config.manifest
+      );
+    }
 
     let tree_config = TreeConfig {
       remote: remote_config.name.clone(),
       branch: branch.into(),
       manifest: remote_config.manifest.clone(),
+      manifest_file: manifest_file.map(String::from),
       tags: Vec::new(),
       projects: Vec::new(),
       group_filters: Some(group_filters),
+      hook_approvals: BTreeMap::new(),
     };
 
     let tree = Tree {
       path: tree_root.clone(),
       config: tree_config,
+      lock: Some(lock),
     };
 
     tree.write_config()?;
@@ -243,7 +400,11 @@ impl Tree {
     let path: PathBuf = path.into();
     if path.join(".pore").exists() {
       let config = Tree::read_config(&path)?;
-      Ok(Tree { path, config })
+      Ok(Tree {
+        path,
+        config,
+        lock: None,
+      })
     } else {
       Err(format_err!("failed to find tree at {:?}", path))
     }
@@ -263,6 +424,18 @@ impl Tree {
     Tree::from_path(path)
   }
 
+  fn acquire_lock(tree_root: &Path) -> Result<FileLock, Error> {
+    FileLock::acquire(tree_root.join(".pore").join("lock"), &format!("tree {:?}", tree_root))
+  }
+
+  /// Lock the tree against other pore processes, until the Tree is dropped.
+  pub fn lock(&mut self) -> Result<(), Error> {
+    if self.lock.is_none() {
+      self.lock = Some(Tree::acquire_lock(&self.path)?);
+    }
+    Ok(())
+  }
+
   fn write_config(&self) -> Result<(), Error> {
     let text = toml::to_string_pretty(&self.config).context("failed to serialize tree config")?;
     Ok(std::fs::write(self.path.join(".pore").join("tree.toml"), text).context("failed to write tree config")?)
@@ -276,23 +449,106 @@ impl Tree {
   }
 
   fn read_manifest(&self) -> Result<Manifest, Error> {
-    let manifest_path = self.path.join(".pore").join("manifest.xml");
-    let manifest = Manifest::parse_file(&manifest_path).context("failed to read manifest")?;
+    let pore_path = self.path.join(".pore");
+    let manifest_path = match &self.config.manifest_file {
+      Some(manifest_file) => pore_path.join("manifest").join(manifest_file),
+      None => pore_path.join("manifest.xml"),
+    };
+    let mut manifest =
+      Manifest::parse_file_in_dir(&manifest_path, pore_path.join("manifest")).context("failed to read manifest")?;
+
+    // Apply local manifests on top of the manifest, in sorted order.
+    let local_manifests_path = pore_path.join("local_manifests");
+    if local_manifests_path.exists() {
+      let entries = std::fs::read_dir(&local_manifests_path)
+        .context(format!("failed to read directory {:?}", local_manifests_path))?;
+
+      let mut local_manifests = Vec::new();
+      for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("xml".as_ref()) {
+          local_manifests.push(path);
+        }
+      }
+      local_manifests.sort();
+
+      for path in local_manifests {
+        manifest
+          .merge_file(&path, &local_manifests_path)
+          .context(format!("failed to read local manifest {:?}", path))?;
+      }
+    }
+
     Ok(manifest)
   }
 
+  /// Resolve the remotes declared in the manifest, relative to the remote that the manifest was fetched from.
+  ///
+  /// Settings like authentication come from the matching remote in the config, if there is one, or else from the
+  /// remote that the manifest was fetched from.
+  fn resolve_remotes(
+    config: &Config,
+    manifest: &Manifest,
+    remote_config: &RemoteConfig,
+  ) -> Result<HashMap<String, RemoteConfig>, Error> {
+    let manifest_url = remote_config.url.clone() + &remote_config.manifest;
+    let mut remotes = HashMap::new();
+    for (name, remote) in &manifest.remotes {
+      let url = remote
+        .fetch_url(&manifest_url)
+        .context(format!("failed to resolve URL for remote {}", name))?;
+      let base = config
+        .find_manifest_remote(name, &url)
+        .unwrap_or_else(|| remote_config.clone());
+      let resolved = RemoteConfig {
+        name: name.clone(),
+        url,
+        ..base
+      };
+      remotes.insert(name.clone(), resolved);
+    }
+    Ok(remotes)
+  }
+
+  /// Resolve paths given on the command line to paths relative to the root of the tree.
+  fn resolve_paths(&self, under: Option<Vec<&str>>) -> Result<Vec<PathBuf>, Error> {
+    // The correctness of this seems dubious if the paths are accessed via symlinks or mount points,
+    // but repo doesn't handle this either.
+    let tree_root = std::fs::canonicalize(&self.path).context(format_err!("failed to canonicalize tree path"))?;
+    let mut paths = Vec::new();
+    for path in under.unwrap_or_default() {
+      let requested_path =
+        std::fs::canonicalize(&path).context(format_err!("failed to canonicalize requested path '{}'", path))?;
+      paths.push(
+        pathdiff::diff_paths(&requested_path, &tree_root)
+          .ok_or_else(|| format_err!("failed to calculate path diff for {}", path))?,
+      );
+    }
+    Ok(paths)
+  }
+
+  /// Check whether a project contains any of the paths returned by resolve_paths, or if there aren't any.
+  fn path_matches<T: AsRef<Path>>(paths: &[PathBuf], project_path: T) -> bool {
+    paths.is_empty() || paths.iter().any(|path| path.starts_with(&project_path))
+  }
+
   fn collect_manifest_projects(
     &self,
+    config: &Config,
     manifest: &Manifest,
     under: Option<Vec<&str>>,
   ) -> Result<Vec<ProjectInfo>, Error> {
-    // TODO: This assumes that all projects are under the same remote. Either remove this assumption or assert it?
     let default_revision = manifest
       .default
       .as_ref()
       .and_then(|def| def.revision.clone())
       .unwrap_or_else(|| self.config.branch.clone());
 
+    // Projects without a remote in a manifest that doesn't specify a default one are fetched from the tree's remote.
+    let remote_config = config.find_remote(&self.config.remote)?;
+    let remotes = Tree::resolve_remotes(config, manifest, &remote_config)?;
+    let default_remote = manifest.default.as_ref().and_then(|def| def.remote.clone());
+
     let group_filters = self
       .config
       .group_filters
@@ -300,35 +556,33 @@ impl Tree {
       .map(|vec| vec.as_slice())
       .unwrap_or(&[]);
 
-    // The correctness of this seems dubious if the paths are accessed via symlinks or mount points,
-    // but repo doesn't handle this either.
-    let tree_root = std::fs::canonicalize(&self.path).context(format_err!("failed to canonicalize tree path"))?;
-    let mut paths = Vec::new();
-    for path in under.unwrap_or_default() {
-      let requested_path =
-        std::fs::canonicalize(&path).context(format_err!("failed to canonicalize requested path '{}'", path))?;
-      paths.push(
-        pathdiff::diff_paths(&requested_path, &tree_root)
-          .ok_or_else(|| format_err!("failed to calculate path diff for {}", path))?,
-      );
-    }
-
-    Ok(
-      manifest
-        .projects
-        .iter()
-        .filter(|(project_path, project)| GroupFilter::filter_project(&group_filters, &project))
-        .filter(|(project_path, _)| {
-          paths.is_empty() || paths.iter().any(|path| Path::new(path).starts_with(project_path))
-        })
-        .map(|(project_path, project)| ProjectInfo {
+    let paths = self.resolve_paths(under)?;
+    manifest
+      .projects
+      .iter()
+      .filter(|(project_path, project)| GroupFilter::filter_project(&group_filters, &project))
+      .filter(|(project_path, _)| Tree::path_matches(&paths, project_path))
+      .map(|(project_path, project)| {
+        let remote = match project.remote.as_ref().or(default_remote.as_ref()) {
+          Some(remote_name) => remotes
+            .get(remote_name)
+            .ok_or_else(|| format_err!("project {} refers to unknown remote {}", project.name, remote_name))?
+            .clone(),
+          None => remote_config.clone(),
+        };
+
+        Ok(ProjectInfo {
           project_path: project_path.to_str().expect("project path not UTF-8").into(),
           project_name: project.name.clone(),
+          remote,
           revision: project.revision.clone().unwrap_or_else(|| default_revision.clone()),
+          upstream: project.upstream.clone(),
+          dest_branch: project.dest_branch.clone(),
+          clone_depth: project.clone_depth,
           file_ops: project.file_operations.clone(),
         })
-        .collect(),
-    )
+      })
+      .collect()
   }
 
   fn progress_bar_style(project_count: usize) -> indicatif::ProgressStyle {
@@ -344,49 +598,150 @@ impl Tree {
     &mut self,
     pool: &mut ThreadPool,
     depot: &Depot,
-    remote_config: &RemoteConfig,
     projects: Vec<ProjectInfo>,
     fetch: bool,
     checkout: CheckoutType,
   ) -> Result<i32, Error> {
-    let remote_config = Arc::new(remote_config.clone());
     let depot: Arc<Depot> = Arc::new(depot.clone());
     let projects: Vec<Arc<_>> = projects.into_iter().map(Arc::new).collect();
     let project_count = projects.len();
     let style = Tree::progress_bar_style(project_count);
 
     if fetch {
-      let pb = Arc::new(indicatif::ProgressBar::new(project_count as u64));
-      pb.set_style(style.clone());
+      // Projects can be checked out more than once (at different paths, or at different revisions), so fetch each
+      // objects mirror once, with everything that its checkouts need.
+      struct FetchJob {
+        remote: RemoteConfig,
+        project_name: String,
+        revisions: Vec<String>,
+        depth: Option<u32>,
+        project_paths: Vec<String>,
+      }
+
+      let mut jobs: BTreeMap<(String, String), FetchJob> = BTreeMap::new();
+      for project in &projects {
+        let key = (project.remote.name.clone(), project.project_name.clone());
+        let revision = project.fetch_revision().to_string();
+        match jobs.get_mut(&key) {
+          Some(job) => {
+            if !job.revisions.contains(&revision) {
+              job.revisions.push(revision);
+            }
+
+            // Fetch as much history as the deepest checkout needs.
+            job.depth = match (job.depth, project.clone_depth) {
+              (Some(a), Some(b)) => Some(a.max(b)),
+              _ => None,
+            };
+            job.project_paths.push(project.project_path.clone());
+          }
+
+          None => {
+            jobs.insert(
+              key,
+              FetchJob {
+                remote: project.remote.clone(),
+                project_name: project.project_name.clone(),
+                revisions: vec![revision],
+                depth: project.clone_depth,
+                project_paths: vec![project.project_path.clone()],
+              },
+            );
+          }
+        }
+      }
+
+      // MultiProgress can't be shared between threads, so every bar is added up front. A project's bar stays invisible
+      // until its fetch starts reporting progress, and disappears again when it's done.
+      let multi_progress = indicatif::MultiProgress::new();
+      let pb = Arc::new(multi_progress.add(indicatif::ProgressBar::new(jobs.len() as u64)));
+      pb.set_style(Tree::progress_bar_style(jobs.len()));
       pb.set_prefix("fetching");
       pb.enable_steady_tick(1000);
+
+      let throughput = Arc::new(multi_progress.add(indicatif::ProgressBar::new_spinner()));
+      throughput.set_style(indicatif::ProgressStyle::default_spinner().template("           {msg}"));
+      let received_bytes = Arc::new(AtomicUsize::new(0));
+      let start = Instant::now();
+
+      let transfer_style = indicatif::ProgressStyle::default_bar()
+        .template("  {prefix:.bold} {bar:20.cyan/blue} {pos}/{len} objects, {msg}")
+        .progress_chars("##-");
+
       let mut handles = Vec::new();
-      for project in &projects {
+      for job in jobs.into_values() {
         let depot = Arc::clone(&depot);
-        let remote_config = Arc::clone(&remote_config);
-        let project_info = Arc::clone(&project);
         let pb = Arc::clone(&pb);
+        let throughput = Arc::clone(&throughput);
+        let received_bytes = Arc::clone(&received_bytes);
+        let transfer = multi_progress.add(indicatif::ProgressBar::new(0));
+        transfer.set_style(transfer_style.clone());
 
         let handle = pool
-          .spawn_with_handle(future::lazy(move |_| {
-            let result = depot.fetch_repo(
-              &remote_config,
-              &project_info.project_name,
-              &project_info.revision,
-              None,
-              None,
-            );
-            pb.set_message(&project_info.project_name);
+          .spawn_with_handle(future::lazy(move |_| -> Result<(), Error> {
+            let mut job_bytes = 0;
+            let mut last_update: Option<Instant> = None;
+            let mut report = |stats: &TransferProgress| {
+              received_bytes.fetch_add(stats.received_bytes.saturating_sub(job_bytes), Ordering::SeqCst);
+              job_bytes = stats.received_bytes;
+
+              // Progress is reported far more often than it's worth redrawing.
+              if last_update.is_some_and(|last| last.elapsed() < Duration::from_millis(100)) {
+                return;
+              }
+              if last_update.is_none() {
+                transfer.set_prefix(&job.project_name);
+              }
+              last_update = Some(Instant::now());
+
+              transfer.set_length(stats.total_objects as u64);
+              transfer.set_position(stats.received_objects as u64);
+              transfer.set_message(&indicatif::HumanBytes(job_bytes as u64).to_string());
+
+              let total = received_bytes.load(Ordering::SeqCst) as u64;
+              let elapsed = start.elapsed().as_millis().max(1) as u64;
+              throughput.set_message(&format!(
+                "received {} at {}/s",
+                indicatif::HumanBytes(total),
+                indicatif::HumanBytes(total * 1000 / elapsed)
+              ));
+            };
+
+            let revisions: Vec<&str> = job.revisions.iter().map(String::as_str).collect();
+            let result = depot
+              .fetch_repo(&job.remote, &job.project_name, &revisions, job.depth, Some(&mut report))
+              .context(format!(
+                "failed to fetch {} for {}",
+                job.project_name,
+                job.project_paths.join(", ")
+              ));
+            transfer.finish_and_clear();
+            pb.set_message(&job.project_name);
             pb.inc(1);
-            result
+            result.map_err(Error::from)
           }))
           .map_err(|err| format_err!("failed to spawn job to fetch"))?;
         handles.push(handle);
       }
 
+      let draw_thread = std::thread::spawn(move || multi_progress.join());
       let handles = pool.run(future::join_all(handles));
       pb.finish();
 
+      let total = received_bytes.load(Ordering::SeqCst) as u64;
+      if total == 0 {
+        throughput.finish_and_clear();
+      } else {
+        throughput.finish_with_message(&format!(
+          "received {} in {}",
+          indicatif::HumanBytes(total),
+          indicatif::HumanDuration(start.elapsed())
+        ));
+      }
+      draw_thread
+        .join()
+        .map_err(|_| format_err!("progress display thread panicked"))??;
+
       let errors: Vec<_> = handles
         .into_iter()
         .filter(Result::is_err)
@@ -406,114 +761,187 @@ impl Tree {
       pb.set_style(style.clone());
       pb.set_prefix("checkout");
       pb.enable_steady_tick(1000);
-      let mut checkout_handles = Vec::new();
       let tree_root = Arc::new(self.path.clone());
 
+      // Projects can be nested inside of other projects, and a parent has to be checked out before its children, or
+      // the child's directory will be in the way. Each project's job is only spawned once its closest parent's job has
+      // finished, so that unrelated projects don't wait on each other, and no job is tied up waiting.
+      let mut projects = projects;
+      projects.sort_by(|a, b| Path::new(&a.project_path).cmp(Path::new(&b.project_path)));
+      let paths: HashSet<&Path> = projects
+        .iter()
+        .map(|project| Path::new(&project.project_path))
+        .collect();
+      let mut nested: HashMap<&Path, Vec<String>> = HashMap::new();
+      let mut parents: HashMap<&Path, &Path> = HashMap::new();
       for project in &projects {
+        let path = Path::new(&project.project_path);
+        if let Some(parent) = path.ancestors().skip(1).find(|ancestor| paths.contains(ancestor)) {
+          let relative = path.strip_prefix(parent).unwrap().to_str().unwrap();
+          nested.entry(parent).or_default().push(relative.to_string());
+          parents.insert(path, parent);
+        }
+      }
+
+      // Build the jobs from the most deeply nested projects up, so that every child's job exists by the time its
+      // parent's job is chained to it.
+      type CheckoutResult = (String, Result<Option<BranchUpdate>, Error>);
+      let mut children: HashMap<&Path, Vec<BoxFuture<Vec<CheckoutResult>>>> = HashMap::new();
+      let mut roots = Vec::new();
+      for project in projects.iter().rev() {
+        let path = Path::new(&project.project_path);
         let depot = Arc::clone(&depot);
-        let remote_config = Arc::clone(&remote_config);
         let project_info = Arc::clone(&project);
         let project_path = self.path.join(&project.project_path);
         let pb = Arc::clone(&pb);
         let tree_root = Arc::clone(&tree_root);
-
-        let handle = pool
-          .spawn_with_handle(future::lazy(move |_| -> (String, Option<Error>) {
-            let project_name = &project_info.project_name;
-            let revision = &project_info.revision;
-
-            let result = || -> Result<(), Error> {
-              if project_path.exists() {
-                depot
-                  .update_remote_refs(&remote_config, &project_name, &project_path)
-                  .context(format_err!("failed to update remote refs"))?;
-
-                let repo = git2::Repository::open(&project_path).context("failed to open repository".to_string())?;
-
-                // There's two things to be concerned about here:
-                //  - HEAD might be attached to a branch
-                //  - the repo might have uncommitted changes in the index or worktree
-                //
-                // If HEAD is attached to a branch, we choose to do nothing (for now). At some point, we should probably
-                // try to perform the equivalent of `git pull --rebase`.
-                //
-                // If the repo has uncommitted changes, do a dry-run first, and give up if we have any conflicts.
-                let head_detached = repo
-                  .head_detached()
-                  .context(format_err!("failed to check if HEAD is detached"))?;
-                let current_head = repo.head().context(format_err!("failed to get HEAD"))?;
-
-                if !head_detached {
-                  let branch_name = current_head
-                    .shorthand()
-                    .ok_or_else(|| format_err!("failed to get shorthand for HEAD"))?;
-                  bail!("currently on a branch ({})", branch_name);
-                } else {
-                  let new_head = util::parse_revision(&repo, &remote_config.name, &revision)
-                    .context("failed to find revision to sync to".to_string())?;
-
-                  // Current head can't be a symbolic reference, because it has to be detached.
-                  let current_head = current_head
-                    .target()
-                    .ok_or_else(|| format_err!("failed to get target of HEAD"))?;
-
-                  // Only do anything if we're not already on the new HEAD.
-                  if current_head != new_head.id() {
-                    let probe = repo.checkout_tree(&new_head, Some(git2::build::CheckoutBuilder::new().dry_run()));
-                    if let Err(err) = probe {
-                      bail!(err);
-                    }
-
-                    repo
-                      .checkout_tree(&new_head, None)
-                      .context(format!("failed to checkout to {:?}", new_head))?;
-
-                    repo
-                      .set_head_detached(new_head.id())
-                      .context(format_err!("failed to detach HEAD"))?;
+        let nested = nested.remove(path).unwrap_or_default();
+        let project_name = project.project_name.clone();
+        let mut job_pool = pool.clone();
+
+        // Children were added in reverse path order.
+        let mut job_children = children.remove(path).unwrap_or_default();
+        job_children.reverse();
+
+        let checkout = future::lazy(move |_| -> CheckoutResult {
+          let project_name = &project_info.project_name;
+          let remote_config = &project_info.remote;
+          let revision = &project_info.revision;
+
+          let result = || -> Result<Option<BranchUpdate>, Error> {
+            let mut update = None;
+            if project_path.exists() {
+              depot
+                .update_remote_refs(remote_config, &project_name, &project_path)
+                .context(format_err!("failed to update remote refs"))?;
+
+              let repo = git2::Repository::open(&project_path).context("failed to open repository".to_string())?;
+
+              // There's two things to be concerned about here:
+              //  - HEAD might be attached to a branch
+              //  - the repo might have uncommitted changes in the index or worktree
+              //
+              // If HEAD is attached to a branch that tracks the manifest revision, we do the equivalent of
+              // `git pull --rebase`. Branches tracking anything else are left alone.
+              //
+              // If the repo has uncommitted changes, do a dry-run first, and give up if we have any conflicts.
+              let head_detached = repo
+                .head_detached()
+                .context(format_err!("failed to check if HEAD is detached"))?;
+              let current_head = repo.head().context(format_err!("failed to get HEAD"))?;
+
+              let new_head = util::parse_revision(&repo, &remote_config.name, &revision)
+                .context("failed to find revision to sync to".to_string())?;
+              let objects_path = depot.objects_mirror(project_name.as_str());
+              depot.fetch_checkout_objects(
+                remote_config,
+                project_name,
+                &objects_path,
+                &[new_head.peel_to_commit()?.id()],
+              )?;
+
+              if !head_detached {
+                let new_head = new_head.peel_to_commit().context("failed to peel revision to commit")?;
+                let fetch_objects = |commits: &[git2::Oid]| {
+                  depot.fetch_checkout_objects(remote_config, project_name, &project_path, commits)
+                };
+                update = Tree::update_branch(&repo, &project_info, current_head, &new_head, &fetch_objects)?;
+              } else {
+                // Current head can't be a symbolic reference, because it has to be detached.
+                let current_head = current_head
+                  .target()
+                  .ok_or_else(|| format_err!("failed to get target of HEAD"))?;
+
+                // Only do anything if we're not already on the new HEAD.
+                if current_head != new_head.id() {
+                  let probe = repo.checkout_tree(&new_head, Some(git2::build::CheckoutBuilder::new().dry_run()));
+                  if let Err(err) = probe {
+                    bail!(err);
                   }
+
+                  repo
+                    .checkout_tree(&new_head, None)
+                    .context(format!("failed to checkout to {:?}", new_head))?;
+
+                  repo
+                    .set_head_detached(new_head.id())
+                    .context(format_err!("failed to detach HEAD"))?;
                 }
-              } else {
-                depot.clone_repo(&remote_config, &project_name, &revision, &project_path)?;
               }
+            } else {
+              depot.clone_repo(remote_config, &project_name, &revision, &project_path)?;
+            }
 
-              // Set up symlinks to repo hooks.
-              let hooks_dir = project_path.join(".git").join("hooks");
-              let relpath = pathdiff::diff_paths(&tree_root, &hooks_dir)
-                .ok_or_else(|| format_err!("failed to calculate path diff from hooks to tree root"))?
-                .join(".pore")
-                .join("hooks");
-              for filename in hooks::hooks().keys() {
-                let target = relpath.join(filename);
-                let symlink_path = hooks_dir.join(filename);
-                let _ = std::fs::remove_file(&symlink_path);
-                std::os::unix::fs::symlink(&target, &symlink_path)
-                  .context(format_err!("failed to create symlink at {:?}", &symlink_path))?;
-              }
+            Tree::link_hooks(&tree_root, &project_path)?;
+            Tree::exclude_nested(&project_path, &nested)?;
+            Ok(update)
+          }();
 
-              Ok(())
-            }();
+          pb.set_message(&project_info.project_name);
+          pb.inc(1);
 
-            pb.set_message(&project_info.project_name);
-            pb.inc(1);
+          (project_info.project_name.clone(), result)
+        });
 
-            (project_info.project_name.clone(), result.err())
-          }))
-          .map_err(|err| format_err!("failed to spawn job to checkout repo"))?;
-        checkout_handles.push(handle);
-      }
+        let job = future::lazy(move |_| job_pool.spawn_with_handle(checkout))
+          .then(move |handle| match handle {
+            Ok(handle) => future::Either::Left(handle),
+            Err(_) => future::Either::Right(future::ready((
+              project_name,
+              Err(format_err!("failed to spawn job to checkout repo")),
+            ))),
+          })
+          .then(move |result| {
+            future::join_all(job_children)
+              .map(move |results| std::iter::once(result).chain(results.into_iter().flatten()).collect())
+          })
+          .boxed();
 
-      let checkout_handles = pool.run(future::join_all(checkout_handles));
+        match parents.get(path) {
+          Some(parent) => children.entry(parent).or_default().push(job),
+          None => roots.push(job),
+        }
+      }
+      roots.reverse();
+      let checkout_results: Vec<CheckoutResult> = pool.run(future::join_all(roots)).into_iter().flatten().collect();
       pb.finish();
 
-      for handle in checkout_handles {
-        match handle {
-          (project_path, Some(error)) => {
+      let mut rebased = Vec::new();
+      let mut fast_forwarded = Vec::new();
+      let mut skipped = Vec::new();
+      for result in checkout_results {
+        match result {
+          (project_path, Err(error)) => {
             println!("{}", console::style(project_path).bold());
             println!("{}", console::style(format!("  {}", error)).red());
           }
 
-          (project_path, None) => {}
+          (project_path, Ok(Some(BranchUpdate::Rebased(branch)))) => {
+            rebased.push(format!("{} ({})", project_path, branch));
+          }
+
+          (project_path, Ok(Some(BranchUpdate::FastForwarded(branch)))) => {
+            fast_forwarded.push(format!("{} ({})", project_path, branch));
+          }
+
+          (project_path, Ok(Some(BranchUpdate::Skipped { branch, reason }))) => {
+            skipped.push(format!("{} ({}): {}", project_path, branch, reason));
+          }
+
+          (project_path, Ok(None)) => {}
+        }
+      }
+
+      for (heading, updates) in &[
+        ("rebased", rebased),
+        ("fast-forwarded", fast_forwarded),
+        ("skipped", skipped),
+      ] {
+        if !updates.is_empty() {
+          println!("{}", console::style(format!("{} branches:", heading)).bold());
+          for update in updates {
+            println!("  {}", update);
+          }
         }
       }
 
@@ -555,15 +983,357 @@ impl Tree {
           }
         }
       }
-
-      // TODO: Figure out repos that have been removed, and warn about them (or delete them?)
-      self.config.projects = projects.iter().map(|p| p.project_path.clone()).collect();
-      self.write_config().context("failed to write tree config")?;
     }
 
     Ok(0)
   }
 
+  /// Bring the branch that HEAD is on up to date with the project's revision, like `git pull --rebase` would.
+  fn update_branch(
+    repo: &git2::Repository,
+    project: &ProjectInfo,
+    head: git2::Reference,
+    target: &git2::Commit,
+    fetch_objects: &dyn Fn(&[git2::Oid]) -> Result<(), Error>,
+  ) -> Result<Option<BranchUpdate>, Error> {
+    let branch_name = head
+      .shorthand()
+      .ok_or_else(|| format_err!("failed to get shorthand for HEAD"))?
+      .to_string();
+    let branch_ref = head
+      .name()
+      .ok_or_else(|| format_err!("branch has name with invalid UTF-8"))?
+      .to_string();
+    let head_id = head
+      .target()
+      .ok_or_else(|| format_err!("failed to get target of HEAD"))?;
+    let target_id = target.id();
+
+    let skip = |reason: String| {
+      Ok(Some(BranchUpdate::Skipped {
+        branch: branch_name.clone(),
+        reason,
+      }))
+    };
+
+    // Only touch branches that track the revision in the manifest.
+    let expected_upstream = format!("{}/{}", project.remote.name, project.fetch_revision());
+    let upstream = git2::Branch::wrap(head)
+      .upstream()
+      .ok()
+      .and_then(|upstream| upstream.name().ok().and_then(|name| name.map(String::from)));
+    if upstream.as_ref() != Some(&expected_upstream) {
+      let upstream = upstream.unwrap_or_else(|| "nothing".into());
+      return skip(format!("tracks {}, not {}", upstream, expected_upstream));
+    }
+
+    if head_id == target_id || repo.graph_descendant_of(head_id, target_id)? {
+      return Ok(None);
+    }
+
+    let fast_forward = repo.graph_descendant_of(target_id, head_id)?;
+    let new_id = if fast_forward {
+      target_id
+    } else {
+      // Rebasing diffs each local commit against its parent, so their blobs have to be present too.
+      let mut commits = Vec::new();
+      let mut revwalk = repo.revwalk()?;
+      revwalk.push(head_id)?;
+      revwalk.hide(target_id)?;
+      for commit in revwalk {
+        let commit = repo.find_commit(commit?)?;
+        commits.push(commit.id());
+        commits.extend(commit.parent_ids());
+      }
+      fetch_objects(&commits)?;
+
+      match Tree::rebase_in_memory(repo, head_id, target_id)? {
+        Some(new_id) => new_id,
+        None => return skip("rebase would conflict".into()),
+      }
+    };
+
+    let new_commit = repo.find_commit(new_id)?;
+    let probe = repo.checkout_tree(
+      new_commit.as_object(),
+      Some(git2::build::CheckoutBuilder::new().dry_run()),
+    );
+    if let Err(err) = probe {
+      return skip(format!("{}", err));
+    }
+
+    repo
+      .checkout_tree(new_commit.as_object(), None)
+      .context(format!("failed to checkout to {}", new_id))?;
+    repo
+      .reference(&branch_ref, new_id, true, "pore sync")
+      .context(format!("failed to update {}", branch_ref))?;
+
+    if fast_forward {
+      Ok(Some(BranchUpdate::FastForwarded(branch_name)))
+    } else {
+      Ok(Some(BranchUpdate::Rebased(branch_name)))
+    }
+  }
+
+  /// Rebase the commits in `head` that aren't in `onto` without touching the worktree or any refs.
+  /// Returns the rebased commit, or None if any of the commits conflict.
+  fn rebase_in_memory(repo: &git2::Repository, head: git2::Oid, onto: git2::Oid) -> Result<Option<git2::Oid>, Error> {
+    let committer = repo.signature().context("failed to get committer signature")?;
+    let branch = repo.find_annotated_commit(head)?;
+    let upstream = repo.find_annotated_commit(onto)?;
+
+    let mut options = git2::RebaseOptions::new();
+    options.inmemory(true);
+    let mut rebase = repo
+      .rebase(Some(&branch), Some(&upstream), None, Some(&mut options))
+      .context("failed to start rebase")?;
+
+    let mut new_head = onto;
+    while let Some(operation) = rebase.next() {
+      let original = repo.find_commit(operation?.id())?;
+      if rebase.inmemory_index()?.has_conflicts() {
+        rebase.abort()?;
+        return Ok(None);
+      }
+
+      let author = original.author();
+      let message = original.message().unwrap_or("");
+      match rebase.commit(&author, &committer, message) {
+        Ok(id) => new_head = id,
+
+        // The commit is already upstream.
+        Err(ref err) if err.code() == git2::ErrorCode::Applied => {}
+
+        Err(err) => {
+          rebase.abort()?;
+          bail!("failed to rebase {}: {}", original.id(), err);
+        }
+      }
+    }
+
+    rebase.finish(&committer).context("failed to finish rebase")?;
+    Ok(Some(new_head))
+  }
+
+  /// Make git ignore the projects that are nested inside of a project.
+  fn exclude_nested(project_path: &Path, nested: &[String]) -> Result<(), Error> {
+    if nested.is_empty() {
+      return Ok(());
+    }
+
+    let exclude_path = project_path.join(".git").join("info").join("exclude");
+    let mut exclude = std::fs::read_to_string(&exclude_path).unwrap_or_default();
+    let mut changed = false;
+    for path in nested {
+      let entry = format!("/{}/", path);
+      if !exclude.lines().any(|line| line == entry) {
+        if !exclude.is_empty() && !exclude.ends_with('\n') {
+          exclude.push('\n');
+        }
+        exclude.push_str(&entry);
+        exclude.push('\n');
+        changed = true;
+      }
+    }
+
+    if changed {
+      std::fs::create_dir_all(exclude_path.parent().unwrap())?;
+      std::fs::write(&exclude_path, exclude).context(format!("failed to write {:?}", exclude_path))?;
+    }
+    Ok(())
+  }
+
+  /// Set up symlinks in a project to the hooks in .pore/hooks.
+  fn link_hooks(tree_root: &Path, project_path: &Path) -> Result<(), Error> {
+    let hooks_dir = project_path.join(".git").join("hooks");
+    let relpath = pathdiff::diff_paths(tree_root, &hooks_dir)
+      .ok_or_else(|| format_err!("failed to calculate path diff from hooks to tree root"))?
+      .join(".pore")
+      .join("hooks");
+    for filename in hooks::hooks().keys() {
+      let target = relpath.join(filename);
+      let symlink_path = hooks_dir.join(filename);
+      let _ = std::fs::remove_file(&symlink_path);
+      std::os::unix::fs::symlink(&target, &symlink_path)
+        .context(format_err!("failed to create symlink at {:?}", &symlink_path))?;
+    }
+    Ok(())
+  }
+
+  /// Clean up any directories that only existed to contain a project that was moved or deleted.
+  fn remove_empty_parents(&self, path: &Path) {
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+      if dir == self.path || std::fs::remove_dir(dir).is_err() {
+        break;
+      }
+      parent = dir.parent();
+    }
+  }
+
+  /// Move the checkouts of projects whose path changed in the manifest, to keep their local branches and stashes.
+  fn relocate_projects(&self, depot: &Depot, manifest: &Manifest, projects: &[ProjectInfo]) -> Result<(), Error> {
+    // Only checkouts that aren't in the manifest at all are candidates to be moved: a partial sync mustn't steal a
+    // checkout that's still in use at a path that it wasn't asked to sync.
+    let current: HashSet<&str> = manifest
+      .projects
+      .keys()
+      .map(|path| path.to_str().expect("project path not UTF-8"))
+      .collect();
+
+    let mut former = Vec::new();
+    for project_path in &self.config.projects {
+      if current.contains(project_path.as_str()) {
+        continue;
+      }
+      if let Some(project_name) = depot.checkout_project_name(self.path.join(project_path)) {
+        former.push((project_path.clone(), project_name));
+      }
+    }
+
+    for project in projects {
+      let new_path = self.path.join(&project.project_path);
+      if new_path.exists() {
+        continue;
+      }
+
+      let index = match former.iter().position(|(_, name)| name == &project.project_name) {
+        Some(index) => index,
+        None => continue,
+      };
+      let (old_project_path, _) = former.remove(index);
+      let old_path = self.path.join(&old_project_path);
+
+      // Moving the directory would also move any other projects nested inside of it.
+      let nested = self
+        .config
+        .projects
+        .iter()
+        .map(String::as_str)
+        .chain(current.iter().cloned())
+        .find(|other| {
+          *other != old_project_path
+            && *other != project.project_path
+            && Path::new(other).starts_with(&old_project_path)
+        });
+      if let Some(nested) = nested {
+        eprintln!(
+          "warning: not moving {} to {}: it contains project {}",
+          old_project_path, project.project_path, nested
+        );
+        continue;
+      }
+
+      // If the project moved into its own subdirectory, get it out of the way first.
+      let source_path = if new_path.starts_with(&old_path) {
+        let temp_path = old_path.with_file_name(format!(".{}.pore-move", project.project_name.replace('/', "_")));
+        std::fs::rename(&old_path, &temp_path).context(format!("failed to move {:?}", old_path))?;
+        temp_path
+      } else {
+        old_path.clone()
+      };
+
+      if let Some(parent) = new_path.parent() {
+        std::fs::create_dir_all(parent).context(format!("failed to create directory {:?}", parent))?;
+      }
+      std::fs::rename(&source_path, &new_path)
+        .context(format!("failed to move {:?} to {:?}", source_path, new_path))?;
+      self.remove_empty_parents(&old_path);
+
+      // The hook symlinks are relative, so they need to be recreated at the new depth.
+      Tree::link_hooks(&self.path, &new_path)?;
+
+      println!(
+        "moved {} to {}",
+        console::style(&old_project_path).bold(),
+        console::style(&project.project_path).bold()
+      );
+    }
+
+    Ok(())
+  }
+
+  /// Check that a project can be deleted without losing any work.
+  fn check_removable(project_path: &Path) -> Result<(), Error> {
+    let repo = git2::Repository::open(project_path).context("failed to open repository")?;
+
+    let mut branches = Vec::new();
+    for branch in repo.branches(Some(git2::BranchType::Local))? {
+      let (branch, _) = branch?;
+      branches.push(branch.name()?.unwrap_or("???").to_string());
+    }
+    ensure!(branches.is_empty(), "has local branches: {}", branches.join(", "));
+    ensure!(repo.find_reference("refs/stash").is_err(), "has stashed changes");
+
+    let statuses = repo
+      .statuses(Some(git2::StatusOptions::new().include_untracked(true)))
+      .context("failed to get status")?;
+    ensure!(statuses.is_empty(), "has uncommitted changes");
+
+    let head = repo
+      .head()
+      .and_then(|head| head.peel_to_commit())
+      .context("failed to resolve HEAD")?
+      .id();
+    for reference in repo.references_glob("refs/remotes/*")? {
+      if let Some(target) = reference?.target() {
+        if target == head || repo.graph_descendant_of(target, head)? {
+          return Ok(());
+        }
+      }
+    }
+    bail!("has unpushed commits");
+  }
+
+  /// Report projects that are no longer in the manifest, deleting them if requested and safe to do so.
+  /// Returns the projects that are still checked out.
+  fn handle_removed_projects(
+    &self,
+    removed: Vec<String>,
+    projects: &HashSet<String>,
+    prune: bool,
+  ) -> Result<Vec<String>, Error> {
+    let mut remaining = Vec::new();
+    for project_path in removed {
+      let path = self.path.join(&project_path);
+      if !path.join(".git").exists() {
+        continue;
+      }
+
+      if !prune {
+        eprintln!(
+          "warning: {} is no longer in the manifest (use --prune-removed to delete it)",
+          project_path
+        );
+        remaining.push(project_path);
+        continue;
+      }
+
+      let nested = projects
+        .iter()
+        .find(|other| Path::new(other).starts_with(&project_path));
+      let result = match nested {
+        Some(nested) => Err(format_err!("contains project {}", nested)),
+        None => Tree::check_removable(&path),
+      };
+
+      if let Err(err) = result {
+        println!("{}", console::style(&project_path).bold());
+        println!("{}", console::style(format!("  not removing: {}", err)).red());
+        remaining.push(project_path);
+        continue;
+      }
+
+      std::fs::remove_dir_all(&path).context(format!("failed to delete {:?}", path))?;
+      self.remove_empty_parents(&path);
+
+      println!("removed {}", console::style(&project_path).bold());
+    }
+
+    Ok(remaining)
+  }
+
   pub fn update_hooks(&mut self) -> Result<(), Error> {
     // Just always do this, since it's cheap.
     let hooks_dir = self.path.join(".pore").join("hooks");
@@ -579,57 +1349,154 @@ impl Tree {
     Ok(())
   }
 
+  #[allow(clippy::too_many_arguments)]
   pub fn sync(
     &mut self,
     config: &Config,
     mut pool: &mut ThreadPool,
     depot: &Depot,
     sync_under: Option<Vec<&str>>,
+    manifest_file: Option<&Path>,
     fetch: FetchType,
     checkout: CheckoutType,
+    prune_removed: bool,
   ) -> Result<i32, Error> {
     // Sync the manifest repo first.
     let remote_config = config.find_remote(&self.config.remote)?;
     let manifest = vec![ProjectInfo {
       project_path: ".pore/manifest".into(),
       project_name: self.config.manifest.clone(),
+      remote: remote_config.clone(),
       revision: self.config.branch.clone(),
+      upstream: None,
+      dest_branch: None,
+      clone_depth: None,
       file_ops: Vec::new(),
     }];
 
     self.update_hooks()?;
 
-    self.sync_repos(
-      &mut pool,
-      depot,
-      &remote_config,
-      manifest,
-      fetch == FetchType::Fetch,
-      checkout,
-    )?;
+    self.sync_repos(&mut pool, depot, manifest, fetch == FetchType::Fetch, checkout)?;
+
+    // An explicitly specified manifest replaces the tree's manifest entirely, without local manifests.
+    let manifest = match manifest_file {
+      Some(path) => Manifest::parse_file_in_dir(path, self.path.join(".pore").join("manifest"))
+        .context(format!("failed to read manifest {:?}", path))?,
+      None => self.read_manifest()?,
+    };
+    let partial = sync_under.is_some();
+    let projects = self.collect_manifest_projects(config, &manifest, sync_under)?;
+    let project_paths: HashSet<String> = projects.iter().map(|p| p.project_path.clone()).collect();
+    if checkout == CheckoutType::Checkout {
+      self.relocate_projects(depot, &manifest, &projects)?;
+    }
+    self.sync_repos(&mut pool, depot, projects, fetch != FetchType::NoFetch, checkout)?;
+
+    if checkout == CheckoutType::Checkout {
+      let (previous, removed): (Vec<String>, Vec<String>) = self
+        .config
+        .projects
+        .drain(..)
+        .filter(|path| !project_paths.contains(path))
+        .partition(|_| partial);
+
+      // A partial sync doesn't know about the projects it wasn't asked to sync.
+      let mut tree_projects = previous;
+      tree_projects.extend(self.handle_removed_projects(removed, &project_paths, prune_removed)?);
+      tree_projects.extend(project_paths);
+      tree_projects.sort();
+
+      self.config.projects = tree_projects;
+      self.write_config().context("failed to write tree config")?;
+    }
 
-    let manifest = self.read_manifest()?;
-    let projects = self.collect_manifest_projects(&manifest, sync_under)?;
-    self.sync_repos(
-      &mut pool,
-      depot,
-      &remote_config,
-      projects,
-      fetch != FetchType::NoFetch,
-      checkout,
-    )?;
     Ok(0)
   }
 
-  pub fn status(&self, config: Config, pool: &mut ThreadPool, status_under: Option<Vec<&str>>) -> Result<i32, Error> {
-    ensure!(
-      status_under.is_none(),
-      "limiting status by path is currently unimplemented"
-    );
-    let projects = self.config.projects.clone();
+  /// Get the tree's manifest, with local manifests applied and only the projects that are checked out.
+  /// If `pinned` is set, every project's revision is replaced with the commit currently checked out.
+  pub fn manifest(&self, config: &Config, depot: &Depot, pinned: bool) -> Result<Manifest, Error> {
+    let mut manifest = self.read_manifest()?;
+    let projects = self.collect_manifest_projects(config, &manifest, None)?;
+
+    let mut checked_out = BTreeMap::new();
+    for project_info in projects {
+      let project_path = PathBuf::from(&project_info.project_path);
+      let mut project = manifest
+        .projects
+        .remove(&project_path)
+        .expect("collected project missing from manifest");
+
+      if pinned {
+        let repo = git2::Repository::open(self.path.join(&project_path))
+          .context(format!("failed to open repository {:?}", project_path))?;
+        let head = repo
+          .head()
+          .and_then(|head| head.peel_to_commit())
+          .context(format!("failed to resolve HEAD for repository {:?}", project_path))?
+          .id();
+
+        // Commits that only exist locally can't be synced to by anyone else.
+        let in_depot = git2::Repository::open_bare(depot.objects_mirror(project_info.project_name.as_str()))
+          .map(|repo| repo.find_commit(head).is_ok())
+          .unwrap_or(false);
+        if !in_depot {
+          eprintln!(
+            "warning: {:?} is at {}, which hasn't been fetched from {}",
+            project_path, head, project_info.remote.name
+          );
+        }
+
+        if !util::is_commit_hash(&project_info.revision) {
+          project.upstream = Some(project_info.revision.clone());
+        }
+        project.revision = Some(head.to_string());
+      }
+
+      checked_out.insert(project_path, project);
+    }
+
+    manifest.projects = checked_out;
+    Ok(manifest)
+  }
+
+  /// Find the commit that a manifest revision refers to, along with a name to refer to it by.
+  fn find_manifest_revision(repo: &git2::Repository, remote: &str, revision: &str) -> Option<(String, git2::Oid)> {
+    if util::is_commit_hash(revision) {
+      let oid = git2::Oid::from_str(revision).ok()?;
+      return Some((revision.to_string(), oid));
+    }
+
+    let name = format!("{}/{}", remote, revision.trim_start_matches("refs/heads/"));
+    let reference = repo.find_reference(&format!("refs/remotes/{}", name)).ok()?;
+    Some((name, reference.peel_to_commit().ok()?.id()))
+  }
+
+  pub fn status(
+    &self,
+    config: &Config,
+    pool: &mut ThreadPool,
+    status_under: Option<Vec<&str>>,
+  ) -> Result<Vec<ProjectStatus>, Error> {
+    let paths = self.resolve_paths(status_under)?;
+    let projects: Vec<String> = self
+      .config
+      .projects
+      .iter()
+      .filter(|project| Tree::path_matches(&paths, project))
+      .cloned()
+      .collect();
     let project_count = projects.len();
     let style = Tree::progress_bar_style(project_count);
 
+    // Compare each project against its revision in the manifest, as fetched into refs/remotes by update_remote_refs.
+    let manifest = self.read_manifest()?;
+    let mut revisions: HashMap<String, (String, String)> = self
+      .collect_manifest_projects(config, &manifest, None)?
+      .into_iter()
+      .map(|project| (project.project_path, (project.remote.name, project.revision)))
+      .collect();
+
     let pb = Arc::new(indicatif::ProgressBar::new(project_count as u64));
     pb.set_style(Tree::progress_bar_style(project_count));
     pb.set_prefix("git status");
@@ -640,6 +1507,7 @@ impl Tree {
     for project in projects {
       let pb = Arc::clone(&pb);
       let tree_root = Arc::clone(&tree_root);
+      let revision = revisions.remove(&project);
       let handle = pool
         .spawn_with_handle(future::lazy(move |_| -> Result<ProjectStatus, Error> {
           let path = tree_root.join(&project);
@@ -654,6 +1522,19 @@ impl Tree {
             None
           };
 
+          let mut upstream = None;
+          let (mut ahead, mut behind) = (0, 0);
+          if let (Some((remote, revision)), Some(head)) = (revision, head.target()) {
+            if let Some((name, oid)) = Tree::find_manifest_revision(&repo, &remote, &revision) {
+              let (a, b) = repo
+                .graph_ahead_behind(head, oid)
+                .context(format!("failed to compare HEAD to {} in repository {}", name, project))?;
+              upstream = Some(name);
+              ahead = a;
+              behind = b;
+            }
+          }
+
           let statuses = repo
             .statuses(Some(git2::StatusOptions::new().include_untracked(true)))
             .context(format!("failed to get status of repository {}", project))?;
@@ -703,8 +1584,12 @@ impl Tree {
             .collect();
 
           Ok(ProjectStatus {
-            project_name: project.clone(),
+            project_path: project.clone(),
+            detached: branch.is_none(),
             branch,
+            upstream,
+            ahead,
+            behind,
             files,
           })
         }))
@@ -715,39 +1600,11 @@ impl Tree {
     let results = pool.run(future::join_all(handles));
     pb.finish_and_clear();
 
-    // TODO: Should we be printing the results here, or out in main.rs?
+    let mut statuses = Vec::new();
     let mut errors = Vec::new();
-    let mut dirty = false;
     for result in results {
       match result {
-        Ok(project_status) => {
-          if project_status.branch == None && project_status.files.is_empty() {
-            continue;
-          }
-
-          dirty = true;
-
-          let project_line = console::style(format!("project {:64}", project_status.project_name)).bold();
-          let branch = match project_status.branch {
-            Some(branch) => console::style(format!("branch {}", branch)).bold(),
-            None => console::style("(*** NO BRANCH ***)".to_string()).red(),
-          };
-          println!("{}{}", project_line, branch);
-
-          for file in project_status.files {
-            let index = file.index.to_char().to_uppercase().to_string();
-            let worktree = file.worktree.to_char();
-            let mut line = console::style(format!(" {}{}     {}", index, worktree, file.filename));
-            if file.worktree != FileState::Unchanged {
-              line = line.red();
-            } else {
-              line = line.green();
-            }
-
-            println!("{}", line)
-          }
-        }
-
+        Ok(project_status) => statuses.push(project_status),
         Err(err) => errors.push(err),
       }
     }
@@ -759,21 +1616,56 @@ impl Tree {
       bail!("failed to git status");
     }
 
-    if dirty {
-      Ok(1)
-    } else {
-      Ok(0)
+    statuses.sort_by(|a, b| a.project_path.cmp(&b.project_path));
+    Ok(statuses)
+  }
+
+  /// Find files and directories in the tree that don't belong to a project, other than pore's own files, the
+  /// destinations of copyfiles and linkfiles, and paths in the config's ignore list.
+  pub fn stray_files(&self, config: &Config, stray_under: Option<Vec<&str>>) -> Result<Vec<StrayFile>, Error> {
+    let paths = self.resolve_paths(stray_under)?;
+    let manifest = self.read_manifest()?;
+
+    let mut owned: HashSet<PathBuf> = self.config.projects.iter().map(PathBuf::from).collect();
+    owned.insert(PathBuf::from(".pore"));
+    for project in self.collect_manifest_projects(config, &manifest, None)? {
+      owned.extend(project.file_ops.iter().map(|op| PathBuf::from(op.dst())));
+    }
+    owned.extend(config.stray_ignore().iter().map(PathBuf::from));
+
+    let mut stray = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+      let full_path = self.path.join(&dir);
+      let entries = std::fs::read_dir(&full_path).context(format!("failed to read directory {:?}", full_path))?;
+      for entry in entries {
+        let entry = entry.context(format!("failed to read directory {:?}", full_path))?;
+        let path = dir.join(entry.file_name());
+        if owned.contains(&path) {
+          continue;
+        }
+
+        // Directories that contain projects aren't stray themselves, but can contain stray files.
+        let directory = entry.file_type()?.is_dir();
+        if directory && owned.iter().any(|owned_path| owned_path.starts_with(&path)) {
+          pending.push(path);
+          continue;
+        }
+
+        if Tree::path_matches(&paths, &path) || paths.iter().any(|requested| path.starts_with(requested)) {
+          stray.push(StrayFile {
+            path: path.to_str().expect("path not UTF-8").into(),
+            directory,
+          });
+        }
+      }
     }
+
+    stray.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(stray)
   }
 
-  pub fn start(
-    &self,
-    config: &Config,
-    depot: &Depot,
-    remote_config: &RemoteConfig,
-    branch_name: &str,
-    directory: &Path,
-  ) -> Result<i32, Error> {
+  pub fn start(&self, config: &Config, depot: &Depot, branch_name: &str, directory: &Path) -> Result<i32, Error> {
     let flags = git2::RepositoryOpenFlags::empty();
     let repo = git2::Repository::open_ext(&directory, flags, &self.path).context("failed to find git repository")?;
 
@@ -793,27 +1685,26 @@ impl Tree {
       .ok_or_else(|| format_err!("invalid project path"))?;
 
     let manifest = self.read_manifest()?;
-    let project = manifest
-      .projects
-      .get(project_path)
+    let directory_str = directory
+      .to_str()
+      .ok_or_else(|| format_err!("directory is not UTF-8"))?;
+    let project = self
+      .collect_manifest_projects(config, &manifest, Some(vec![directory_str]))?
+      .into_iter()
+      .find(|project| Path::new(&project.project_path) == project_path)
       .ok_or_else(|| format_err!("failed to find project {:?}", project_path))?;
 
-    // TODO: This assumes that all projects are under the same remote. Either remove this assumption or assert it?
-    let revision = project.revision.clone().unwrap_or_else(|| {
-      manifest
-        .default
-        .and_then(|def| def.revision)
-        .unwrap_or_else(|| self.config.branch.clone())
-    });
+    let remote_name = &project.remote.name;
+    let revision = &project.revision;
 
-    let object = util::parse_revision(&repo, &remote_config.name, &revision)?;
+    let object = util::parse_revision(&repo, remote_name, revision)?;
     let commit = object.peel_to_commit().context("failed to peel object to commit")?;
 
     let mut branch = repo
       .branch(&branch_name, &commit, false)
       .context(format_err!("failed to create branch {}", branch_name))?;
     branch
-      .set_upstream(Some(&format!("{}/{}", remote_config.name, revision)))
+      .set_upstream(Some(&format!("{}/{}", remote_name, project.fetch_revision())))
       .context("failed to set branch upstream")?;
 
     repo.checkout_tree(&object, None)?;
@@ -826,7 +1717,7 @@ impl Tree {
 
   pub fn prune(&self, config: &Config, pool: &mut ThreadPool, depot: &Depot) -> Result<i32, Error> {
     let manifest = self.read_manifest()?;
-    let projects = self.collect_manifest_projects(&manifest, None)?;
+    let projects = self.collect_manifest_projects(config, &manifest, None)?;
     let project_count = projects.len();
 
     let pb = Arc::new(indicatif::ProgressBar::new(project_count as u64));
@@ -944,15 +1835,262 @@ impl Tree {
     }
   }
 
+  /// Run a hook from the manifest's <repo-hooks> project on projects, given as (name, path) pairs, the way repo does,
+  /// asking for approval first if the hook is new or has changed since it was last approved.
+  fn run_repohook(&mut self, manifest: &Manifest, hook: &str, projects: &[(&str, &str)]) -> Result<(), Error> {
+    let repohooks = match &manifest.repohooks {
+      Some(repohooks) => repohooks,
+      None => return Ok(()),
+    };
+
+    let enabled = repohooks
+      .enabled_list
+      .as_deref()
+      .unwrap_or("")
+      .split(|c: char| c == ',' || c.is_whitespace())
+      .any(|name| name == hook);
+    if !enabled {
+      return Ok(());
+    }
+
+    let hooks_project = repohooks
+      .in_project
+      .as_ref()
+      .ok_or_else(|| format_err!("<repo-hooks> doesn't specify in-project"))?;
+    let hooks_path = manifest
+      .projects
+      .iter()
+      .find(|(_, project)| &project.name == hooks_project)
+      .map(|(project_path, _)| self.path.join(project_path))
+      .ok_or_else(|| format_err!("repohooks project {} is not in the manifest", hooks_project))?;
+
+    let script_path = hooks_path.join(format!("{}.py", hook));
+    let script = std::fs::read(&script_path).context(format!("failed to read {} hook {:?}", hook, script_path))?;
+    let hash = git2::Oid::hash_object(git2::ObjectType::Blob, &script)?.to_string();
+    if self.config.hook_approvals.get(hook) != Some(&hash) {
+      println!("the manifest wants to run {:?} (blob {})", script_path, hash);
+      if !util::confirm(&format!("allow the {} hook to run?", hook))? {
+        bail!("{} hook was not approved (use --no-verify to skip it)", hook);
+      }
+      self.config.hook_approvals.insert(hook.to_string(), hash);
+      self.write_config()?;
+    }
+
+    // repo doesn't run hooks as scripts: it executes them in its own interpreter and calls their main() with keyword
+    // arguments describing the projects.
+    const DRIVER: &str = indoc!(
+      "
+      import os
+      import sys
+      script = sys.argv[1]
+      sys.path[0] = os.path.dirname(os.path.abspath(script))
+      context = {'__file__': script}
+      with open(script) as f:
+          exec(compile(f.read(), script, 'exec'), context)
+      context['main'](project_list=sys.argv[2::2], worktree_list=sys.argv[3::2], hook_should_take_kwargs=True)
+      "
+    );
+
+    let mut cmd = std::process::Command::new("python3");
+    cmd.arg("-c").arg(DRIVER).arg(&script_path).current_dir(&self.path);
+    for (project_name, project_path) in projects {
+      cmd.arg(project_name).arg(self.path.join(project_path));
+    }
+
+    let status = cmd.status().context(format!("failed to run {} hook", hook))?;
+    ensure!(
+      status.success(),
+      "{} hook failed (use --no-verify to upload anyway)",
+      hook
+    );
+    Ok(())
+  }
+
+  pub fn upload(
+    &mut self,
+    config: &Config,
+    pool: &mut ThreadPool,
+    upload_under: Option<Vec<&str>>,
+    options: &UploadOptions,
+  ) -> Result<i32, Error> {
+    let manifest = self.read_manifest()?;
+    let projects = self.collect_manifest_projects(config, &manifest, upload_under)?;
+
+    struct PendingUpload {
+      project_name: String,
+      project_path: String,
+      remote: String,
+      branch: String,
+      dest_branch: String,
+      commits: Vec<String>,
+    }
+
+    let tree_root = Arc::new(self.path.clone());
+    let mut handles = Vec::new();
+    for project in projects {
+      let tree_root = Arc::clone(&tree_root);
+      let handle = pool
+        .spawn_with_handle(future::lazy(move |_| -> Result<Vec<PendingUpload>, Error> {
+          let path = tree_root.join(&project.project_path);
+          let repo =
+            git2::Repository::open(&path).context(format!("failed to open repository {:?}", project.project_path))?;
+
+          let mut uploads = Vec::new();
+          for branch in repo.branches(Some(git2::BranchType::Local))? {
+            let (branch, _) = branch?;
+            let upstream = match branch.upstream() {
+              Ok(upstream) => upstream,
+              Err(_) => continue,
+            };
+
+            let branch_name = branch
+              .name()?
+              .ok_or_else(|| format_err!("branch has name with invalid UTF-8"))?
+              .to_string();
+            let local = branch
+              .get()
+              .target()
+              .ok_or_else(|| format_err!("failed to resolve branch {}", branch_name))?;
+            let upstream = upstream
+              .get()
+              .target()
+              .ok_or_else(|| format_err!("failed to resolve upstream of branch {}", branch_name))?;
+
+            let mut revwalk = repo.revwalk()?;
+            revwalk.push(local)?;
+            revwalk.hide(upstream)?;
+            let mut commits = Vec::new();
+            for oid in revwalk {
+              let commit = repo.find_commit(oid?)?;
+              let id = commit.as_object().short_id()?;
+              commits.push(format!(
+                "{} {}",
+                id.as_str().unwrap_or(""),
+                commit.summary().unwrap_or("")
+              ));
+            }
+
+            if !commits.is_empty() {
+              uploads.push(PendingUpload {
+                project_name: project.project_name.clone(),
+                project_path: project.project_path.clone(),
+                remote: project.remote.name.clone(),
+                branch: branch_name,
+                dest_branch: project.upload_branch().to_string(),
+                commits,
+              });
+            }
+          }
+          Ok(uploads)
+        }))
+        .map_err(|err| format_err!("failed to spawn job"))?;
+      handles.push(handle);
+    }
+
+    let mut uploads = Vec::new();
+    for result in pool.run(future::join_all(handles)) {
+      uploads.extend(result?);
+    }
+
+    if uploads.is_empty() {
+      println!("no branches ready for upload");
+      return Ok(0);
+    }
+
+    for upload in &uploads {
+      println!(
+        "{} (branch {} to {})",
+        console::style(&upload.project_path).bold(),
+        upload.branch,
+        options.destination(&upload.dest_branch)
+      );
+      for commit in &upload.commits {
+        println!("  {}", commit);
+      }
+    }
+
+    if options.dry_run {
+      return Ok(0);
+    }
+
+    if !options.yes && !util::confirm(&format!("upload {} branch(es)?", uploads.len()))? {
+      println!("aborted");
+      return Ok(1);
+    }
+
+    if !options.no_verify {
+      let mut projects: Vec<(&str, &str)> = uploads
+        .iter()
+        .map(|upload| (upload.project_name.as_str(), upload.project_path.as_str()))
+        .collect();
+      projects.dedup();
+      self.run_repohook(&manifest, "pre-upload", &projects)?;
+    }
+
+    // Push with git instead of libgit2, so that credential helpers and ssh configuration work as they do for git.
+    let mut rc = 0;
+    for upload in uploads {
+      let path = self.path.join(&upload.project_path);
+      let refspec = format!(
+        "refs/heads/{}:{}",
+        upload.branch,
+        options.destination(&upload.dest_branch)
+      );
+      let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&path)
+        .arg("push")
+        .arg(&upload.remote)
+        .arg(&refspec)
+        .output()
+        .context("failed to spawn git push")?;
+
+      if output.status.success() {
+        println!(
+          "{}",
+          console::style(format!("uploaded {}", upload.project_path)).green()
+        );
+      } else {
+        println!("{}", console::style(&upload.project_path).bold());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        for line in stderr.lines() {
+          println!("{}", console::style(format!("  {}", line)).red());
+        }
+        rc = 1;
+      }
+    }
+
+    Ok(rc)
+  }
+
   pub fn forall(
     &self,
     config: &Config,
     pool: &mut ThreadPool,
     forall_under: Option<Vec<&str>>,
+    options: &ForallOptions,
     command: &str,
   ) -> Result<i32, Error> {
     let manifest = self.read_manifest()?;
-    let projects = self.collect_manifest_projects(&manifest, forall_under)?;
+    let projects: Vec<ProjectInfo> = self
+      .collect_manifest_projects(config, &manifest, forall_under)?
+      .into_iter()
+      .filter(|project| {
+        let manifest_project = &manifest.projects[Path::new(&project.project_path)];
+        GroupFilter::filter_project(&options.group_filters, manifest_project)
+      })
+      .filter(|project| {
+        if options.regexes.is_empty() {
+          return true;
+        }
+
+        let matched = options
+          .regexes
+          .iter()
+          .any(|regex| regex.is_match(&project.project_name) || regex.is_match(&project.project_path));
+        matched != options.inverse_regex
+      })
+      .collect();
     let project_count = projects.len();
 
     let pb = Arc::new(indicatif::ProgressBar::new(project_count as u64));
@@ -980,13 +2118,40 @@ impl Tree {
           let rel_to_root = pathdiff::diff_paths(&tree_root, &path)
             .ok_or_else(|| format_err!("failed to calculate relative path to root"))?;
 
-          let result = std::process::Command::new("sh")
+          // repo's name for the revision, translated to the remote tracking branch that sync fetches it into.
+          let local_revision = if util::is_commit_hash(&project.revision) || project.revision.starts_with("refs/tags/")
+          {
+            project.revision.clone()
+          } else {
+            format!(
+              "refs/remotes/{}/{}",
+              project.remote.name,
+              project.revision.trim_start_matches("refs/heads/")
+            )
+          };
+
+          let mut cmd = std::process::Command::new("sh");
+          cmd
             .arg("-c")
             .arg(command.deref())
             .env("PORE_ROOT", tree_root.as_os_str())
-            .env("PORE_ROOT_REL", rel_to_root.as_os_str())
-            .current_dir(&path)
-            .output()?;
+            .env("PORE_ROOT_REL", rel_to_root.as_os_str());
+
+          // Export the variables that repo forall does, under both names, so that existing scripts keep working.
+          let vars = [
+            ("PROJECT", project.project_name.as_str()),
+            ("PATH", project.project_path.as_str()),
+            ("REMOTE", project.remote.name.as_str()),
+            ("LREV", local_revision.as_str()),
+            ("RREV", project.revision.as_str()),
+            ("UPSTREAM", project.upstream.as_deref().unwrap_or("")),
+          ];
+          for (name, value) in &vars {
+            cmd.env(format!("REPO_{}", name), value);
+            cmd.env(format!("PORE_{}", name), value);
+          }
+
+          let result = cmd.current_dir(&path).output()?;
 
           // TODO: Rust's process builder API kinda sucks, there's no way to spawn a process with
           //       stdout and stderr being the same pipe, to order their output chronologically.
@@ -1035,3 +2200,200 @@ impl Tree {
     Ok(rc)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testutil::{self, TestServer};
+
+  #[test]
+  fn resolve_remotes() {
+    let config: Config = toml::from_str(
+      r#"
+        [[remotes]]
+        name = "aosp"
+        url = "https://android.example.com/"
+        manifest = "platform/manifest"
+        depot = "android"
+        auth = { netrc = "aosp.netrc" }
+
+        [[remotes]]
+        name = "internal"
+        url = "https://internal.example.com/"
+        manifest = "manifest"
+        depot = "android"
+        auth = { netrc = "internal.netrc" }
+
+        [[remotes]]
+        name = "partner"
+        url = "https://partner.example.com/vendor/"
+        manifest = "manifest"
+        depot = "android"
+        auth = { netrc = "partner.netrc" }
+
+        [depots.android]
+        path = "/android"
+      "#,
+    )
+    .unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let manifest_path = dir.path().join("default.xml");
+    std::fs::write(
+      &manifest_path,
+      r#"<manifest>
+        <remote name="aosp" fetch=".." />
+        <remote name="internal" fetch="https://mirror.example.com/" />
+        <remote name="vendor" fetch="https://partner.example.com/vendor/blobs" />
+        <remote name="other" fetch="https://other.example.com/" />
+        <default remote="aosp" revision="master" />
+      </manifest>"#,
+    )
+    .unwrap();
+    let manifest = Manifest::parse_file(&manifest_path).unwrap();
+
+    let remote_config = config.find_remote("aosp").unwrap();
+    let remotes = Tree::resolve_remotes(&config, &manifest, &remote_config).unwrap();
+    let resolved = |name: &str| {
+      let remote = &remotes[name];
+      assert_eq!(remote.name, name);
+      (remote.url.as_str(), remote.auth.netrc.as_ref().unwrap().as_str())
+    };
+
+    // Remotes are matched by name, then by URL, and fall back to the remote that the manifest came from.
+    assert_eq!(resolved("aosp"), ("https://android.example.com/", "aosp.netrc"));
+    assert_eq!(resolved("internal"), ("https://mirror.example.com/", "internal.netrc"));
+    assert_eq!(
+      resolved("vendor"),
+      ("https://partner.example.com/vendor/blobs/", "partner.netrc")
+    );
+    assert_eq!(resolved("other"), ("https://other.example.com/", "aosp.netrc"));
+  }
+
+  #[test]
+  fn sync_nested_projects() {
+    let projects = ["platform/a", "platform/a/b", "platform/a/b/c", "platform/d"];
+    let server = TestServer::new(&projects);
+    let tree = server.clone_tree("tree");
+
+    for project in &projects {
+      let readme = std::fs::read_to_string(tree.path.join(project).join("README")).unwrap();
+      assert_eq!(&readme, project);
+    }
+
+    // Parents ignore the projects nested inside of them.
+    let status = testutil::git(tree.path.join("platform/a"), &["status", "--porcelain"]);
+    assert_eq!(status, "");
+  }
+
+  #[test]
+  fn upload_destination() {
+    let options = UploadOptions::default();
+    assert_eq!(options.destination("master"), "refs/for/master");
+
+    let options = UploadOptions {
+      topic: Some("feature".into()),
+      reviewers: vec!["a@example.com".into(), "b@example.com".into()],
+      cc: vec!["c@example.com".into()],
+      hashtags: vec!["tag".into()],
+      private: true,
+      wip: true,
+      ..UploadOptions::default()
+    };
+    assert_eq!(
+      options.destination("android10-release"),
+      "refs/for/android10-release%topic=feature,r=a@example.com,r=b@example.com,cc=c@example.com,t=tag,private,wip"
+    );
+  }
+
+  #[test]
+  fn upload_to_bare_repo() {
+    let server = TestServer::new(&["platform/a", "platform/b"]);
+    let mut tree = server.clone_tree("tree");
+
+    let project = tree.path.join("platform/a");
+    testutil::git(&project, &["checkout", "-q", "-b", "feature", "--track", "test/master"]);
+    testutil::commit_file(&project, "feature.txt", "feature");
+
+    let options = UploadOptions {
+      topic: Some("feature".into()),
+      reviewers: vec!["a@example.com".into()],
+      cc: vec!["c@example.com".into()],
+      hashtags: vec!["tag".into()],
+      private: true,
+      wip: true,
+      yes: true,
+      ..UploadOptions::default()
+    };
+    let mut pool = ThreadPool::new().unwrap();
+    assert_eq!(tree.upload(&server.config(), &mut pool, None, &options).unwrap(), 0);
+
+    let uploaded = testutil::git(
+      server.repo_path("platform/a"),
+      &[
+        "rev-parse",
+        "refs/for/master%topic=feature,r=a@example.com,cc=c@example.com,t=tag,private,wip",
+      ],
+    );
+    assert_eq!(uploaded, testutil::git(&project, &["rev-parse", "feature"]));
+
+    // Projects without local commits aren't pushed.
+    let refs = testutil::git(server.repo_path("platform/b"), &["for-each-ref", "refs/for"]);
+    assert_eq!(refs, "");
+  }
+
+  #[test]
+  fn upload_runs_pre_upload_hook() {
+    let server = TestServer::with_manifest_extra(
+      &["platform/a", "tools/repohooks"],
+      "  <repo-hooks in-project=\"tools/repohooks\" enabled-list=\"pre-upload\" />\n",
+    );
+    let hook = indoc!(
+      "
+      import hook_format
+
+      def main(project_list, worktree_list=None, **kwargs):
+          with open('hook-output.txt', 'w') as f:
+              for project, worktree in zip(project_list, worktree_list):
+                  f.write(hook_format.line(project, worktree))
+      "
+    );
+    let hooks_work = server.dir.path().join("work/tools/repohooks");
+    // Hooks can import modules that live next to them.
+    testutil::commit_file(
+      &hooks_work,
+      "hook_format.py",
+      "def line(project, worktree):\n    return project + ' ' + worktree + '\\n'\n",
+    );
+    testutil::commit_file(&hooks_work, "pre-upload.py", hook);
+    testutil::git(
+      &hooks_work,
+      &[
+        "push",
+        "-q",
+        server.repo_path("tools/repohooks").to_str().unwrap(),
+        "master",
+      ],
+    );
+
+    let mut tree = server.clone_tree("tree");
+    let project = tree.path.join("platform/a");
+    testutil::git(&project, &["checkout", "-q", "-b", "feature", "--track", "test/master"]);
+    testutil::commit_file(&project, "feature.txt", "feature");
+
+    // Approve the hook ahead of time, instead of prompting for it.
+    let hash = git2::Oid::hash_object(git2::ObjectType::Blob, hook.as_bytes()).unwrap();
+    tree.config.hook_approvals.insert("pre-upload".into(), hash.to_string());
+
+    let options = UploadOptions {
+      yes: true,
+      ..UploadOptions::default()
+    };
+    let mut pool = ThreadPool::new().unwrap();
+    assert_eq!(tree.upload(&server.config(), &mut pool, None, &options).unwrap(), 0);
+
+    // repo passes project names, and the paths to their worktrees.
+    let output = std::fs::read_to_string(tree.path.join("hook-output.txt")).unwrap();
+    assert_eq!(output, format!("platform/a {}\n", project.display()));
+  }
+}
@@ -16,6 +16,7 @@
 
 use std::fmt::Debug;
 use std::io;
+use std::io::Write;
 use std::path::Path;
 
 use failure::{Error, ResultExt};
@@ -40,6 +41,39 @@ pub fn assert_empty_directory<T: AsRef<Path> + Debug>(directory_path: T) -> Resu
   Ok(())
 }
 
+/// Ask the user a yes/no question on the terminal, defaulting to no.
+pub fn confirm(prompt: &str) -> Result<bool, Error> {
+  print!("{} [y/N] ", prompt);
+  io::stdout().flush()?;
+  let mut answer = String::new();
+  io::stdin().read_line(&mut answer).context("failed to read answer")?;
+  Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Check whether a manifest revision is a full commit hash, rather than a branch or tag name.
+pub fn is_commit_hash(revision: &str) -> bool {
+  revision.len() == 40 && revision.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Check whether a URL is an scp-style ssh URL (e.g. `git@host:path`), which isn't a URL as far as the url crate
+/// is concerned.
+pub fn is_scp_url(url: &str) -> bool {
+  if url.contains("://") {
+    return false;
+  }
+
+  match (url.find(':'), url.find('/')) {
+    (Some(colon), Some(slash)) => colon < slash,
+    (Some(_), None) => true,
+    _ => false,
+  }
+}
+
+/// Quote a string for use as a single word in a shell command.
+pub fn shell_quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "'\\''"))
+}
+
 pub fn parse_revision<T: AsRef<str>, U: AsRef<str>>(
   repo: &git2::Repository,
   remote: T,